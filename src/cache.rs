@@ -0,0 +1,135 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context};
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    AnimationDecoder, Frame,
+};
+
+use crate::{
+    render::Recolor,
+    utils::{self, Animation},
+};
+
+/// Decoded trait images shared by every config, as frames, along with the
+/// recolored copies of variants
+///
+/// Past the memory budget the least recently used images are dropped, and
+/// decoded again the next time a token needs them.
+pub struct ImageCache {
+    budget: usize,
+    state: Mutex<CacheState>,
+}
+
+/// Image path and recolor key, `None` for the image as decoded
+type CacheKey = (PathBuf, Option<String>);
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, (Arc<Vec<Frame>>, u64)>,
+    size: usize,
+    tick: u64,
+}
+
+impl ImageCache {
+    /// Cache holding up to `budget` bytes of decoded pixels
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Frames of a trait image, a still image has a single frame
+    pub fn get(&self, path: &Path) -> anyhow::Result<Arc<Vec<Frame>>> {
+        self.load((path.to_path_buf(), None), || {
+            decode(path).with_context(|| format!("failed to load image {}", path.display()))
+        })
+    }
+
+    /// Frames of a trait image recolored for a variant
+    pub fn get_recolored(&self, path: &Path, recolor: &Recolor) -> anyhow::Result<Arc<Vec<Frame>>> {
+        self.load((path.to_path_buf(), Some(recolor.key())), || {
+            Ok(recolor.apply(&self.get(path)?))
+        })
+    }
+
+    fn load(
+        &self,
+        key: CacheKey,
+        make: impl FnOnce() -> anyhow::Result<Vec<Frame>>,
+    ) -> anyhow::Result<Arc<Vec<Frame>>> {
+        {
+            let mut state = self.state.lock().expect("unable to lock image cache");
+            state.tick += 1;
+            let tick = state.tick;
+            if let Some((image, last_used)) = state.entries.get_mut(&key) {
+                *last_used = tick;
+                return Ok(image.clone());
+            }
+        }
+
+        // made outside of the lock so other threads keep rendering
+        let image = Arc::new(make()?);
+        let size = frames_size(&image);
+
+        let mut state = self.state.lock().expect("unable to lock image cache");
+        while state.size + size > self.budget {
+            let Some(oldest) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some((evicted, _)) = state.entries.remove(&oldest) {
+                state.size -= frames_size(&evicted);
+            }
+        }
+
+        state.tick += 1;
+        let tick = state.tick;
+        if state.entries.insert(key, (image.clone(), tick)).is_none() {
+            state.size += size;
+        }
+
+        Ok(image)
+    }
+}
+
+fn frames_size(frames: &[Frame]) -> usize {
+    frames
+        .iter()
+        .map(|frame| frame.buffer().as_raw().len())
+        .sum()
+}
+
+/// Decodes every frame of animated images
+fn decode(path: &Path) -> anyhow::Result<Vec<Frame>> {
+    let frames = match utils::animation(path)? {
+        Some(Animation::Gif) => GifDecoder::new(BufReader::new(File::open(path)?))?
+            .into_frames()
+            .collect_frames()?,
+        Some(Animation::Apng) => PngDecoder::new(BufReader::new(File::open(path)?))?
+            .apng()
+            .into_frames()
+            .collect_frames()?,
+        Some(Animation::WebP) => WebPDecoder::new(BufReader::new(File::open(path)?))?
+            .into_frames()
+            .collect_frames()?,
+        None => vec![Frame::new(image::open(path)?.to_rgba8())],
+    };
+
+    if frames.is_empty() {
+        return Err(anyhow!("{} has no frames", path.display()));
+    }
+
+    Ok(frames)
+}
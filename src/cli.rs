@@ -1,4 +1,9 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Uniqueness;
 
 #[derive(Parser, Debug)]
 pub struct ConfigArgs {
@@ -13,6 +18,101 @@ pub struct ConfigArgs {
     /// Blacklist name case sentivity, default is false
     #[clap(long, default_value = "false")]
     pub bl_case_sen: bool,
+
+    /// Seed for reproducible generation, a random one is picked and printed when omitted
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    /// Render the tokens of the manifest that are missing from the output
+    #[clap(long, conflicts_with = "extend")]
+    pub resume: bool,
+
+    /// Append this many new unique tokens per config to the existing output
+    #[clap(long)]
+    pub extend: Option<usize>,
+
+    /// Number of the first token, default is 1
+    #[clap(long)]
+    pub start: Option<usize>,
+
+    /// Whether token numbers restart for every config or run across all of them
+    #[clap(long, value_enum)]
+    pub numbering: Option<Numbering>,
+
+    /// Uniqueness scope of every config: global, config or group:<name>
+    #[clap(long)]
+    pub uniqueness: Option<Uniqueness>,
+
+    /// Output folder or manifest of a previous drop whose DNAs can't be generated again
+    #[clap(long)]
+    pub exclude_drop: Vec<PathBuf>,
+
+    /// Memory budget in MB for decoded trait images shared while rendering
+    #[clap(long, default_value = "1024")]
+    pub cache_mb: usize,
+}
+
+#[derive(ValueEnum, Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Numbering {
+    /// Every config counts from the start number
+    #[default]
+    Config,
+    /// Configs continue the count of the previous one
+    Global,
+}
+
+#[derive(Parser, Debug)]
+pub struct PlanArgs {
+    /// Path to the projects config file
+    #[clap(short, long, default_value = "configs")]
+    pub config_folder: String,
+
+    /// Path to blacklist config file
+    #[clap(short, long, default_value = "blacklist.json")]
+    pub bl_file: String,
+
+    /// Blacklist name case sentivity, default is false
+    #[clap(long, default_value = "false")]
+    pub bl_case_sen: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct RevealArgs {
+    /// Path to the projects config file
+    #[clap(short, long, default_value = "configs")]
+    pub config_folder: String,
+
+    /// Committed offset the token numbers are shifted by
+    #[clap(long)]
+    pub starting_index: usize,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DnaCommands {
+    /// Turn a list of traits into a DNA
+    Encode(DnaEncodeArgs),
+    /// Show the traits of a DNA
+    Decode(DnaDecodeArgs),
+}
+
+#[derive(Parser, Debug)]
+pub struct DnaEncodeArgs {
+    /// Path to the projects config file
+    #[clap(short, long, default_value = "configs")]
+    pub config_folder: String,
+
+    /// Name of the config the traits belong to
+    pub config: String,
+
+    /// Traits as `layer=trait`, layers left out get "None"
+    pub traits: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct DnaDecodeArgs {
+    /// Encoded DNA, DNA hash, or path of a generated token file
+    pub dna: String,
 }
 
 /// CLI for generating jpegs
@@ -22,6 +122,13 @@ pub enum Commands {
     Clean,
     /// Generate an NFT collection
     Gen(ConfigArgs),
+    /// Check how many unique combinations each config can produce
+    Plan(PlanArgs),
+    /// Shift the token numbers of the output by a committed starting index
+    Reveal(RevealArgs),
+    /// Encode or decode token DNAs
+    #[clap(subcommand)]
+    Dna(DnaCommands),
 }
 
 impl Default for Commands {
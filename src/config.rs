@@ -3,9 +3,11 @@ use std::{
     fs::{self, File},
     io::Read,
     path::PathBuf,
+    str::FromStr,
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use image::imageops::FilterType;
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
@@ -16,12 +18,36 @@ pub struct AppConfig {
     pub name: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub display_name: Option<String>,
+    /// Where trait weights come from
+    #[serde(default)]
+    pub mode: Mode,
+    /// Weight of every tier folder in simple mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tiers: Option<HashMap<String, u32>>,
     pub amount: usize,
     pub tolerance: usize,
+    #[serde(default)]
+    pub sampling: Sampling,
+    #[serde(default)]
+    pub uniqueness: Uniqueness,
     pub path: PathBuf,
+    /// Canvas size, defaults to the size of the first trait image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// Encoding of the output images, PNG by default
+    #[serde(default)]
+    pub format: Format,
+    /// Extra sizes every token is written at, next to the full size image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sizes: Option<Vec<OutputSize>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub off_traits: Option<HashSet<String>>,
     pub layers: Vec<LayerConfig>,
+    /// Layers drawn as matched pairs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub links: Option<Vec<LinkConfig>>,
     pub extra: Option<Map<String, Value>>,
 
     #[serde(skip)]
@@ -30,6 +56,55 @@ pub struct AppConfig {
     pub bl: Option<HashMap<String, String>>,
 }
 
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// `#WEIGHT` suffixes and sidecars
+    #[default]
+    Advanced,
+    /// Tier folders sharing the tier weight, `<layer>/<tier>/<trait>`
+    Simple,
+}
+
+/// How combinations are drawn
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Sampling {
+    /// Draw random combinations and reject duplicates until `tolerance` is reached
+    #[default]
+    Random,
+    /// Enumerate every combination and draw `amount` of them without replacement
+    Exhaustive,
+}
+
+/// Which configs a DNA has to be unique across
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Uniqueness {
+    /// Unique across every config
+    #[default]
+    Global,
+    /// Unique within the config only
+    Config,
+    /// Unique across the configs of the same group
+    Group(String),
+}
+
+impl FromStr for Uniqueness {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "global" => Ok(Self::Global),
+            "config" => Ok(Self::Config),
+            _ => s
+                .strip_prefix("group:")
+                .map(|group| Self::Group(group.to_string()))
+                .ok_or_else(|| anyhow!("{} is not global, config or group:<name>", s)),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Default)]
 pub struct SetConfig {
     pub name: String,
@@ -43,8 +118,312 @@ pub struct LayerConfig {
     pub display_name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub none: Option<u32>,
+    /// Treat the trait weights and `none` as exact counts summing to `amount`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub exclude_if_traits: Option<Vec<IfTrait>>,
+    /// Pins the layer to some of its traits when earlier layers match, the
+    /// first matching rule wins
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub require_if_traits: Option<Vec<RequireTrait>>,
+    /// Attribute the tier of the trait is written to in simple mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tier_attribute: Option<String>,
+    /// Recolored copies of trait images, each one a trait of its own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variants: Option<Vec<VariantConfig>>,
+    /// Traits drawn from a color or a gradient instead of an image file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fills: Option<Vec<FillTrait>>,
+    /// How the layer is blended onto the layers below it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blend: Option<Blend>,
+    /// Opacity of the layer, from 0 to 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub opacity: Option<f32>,
+    /// Where trait images are placed on the canvas
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anchor: Option<Anchor>,
+    /// Offset from the anchor, in pixels
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<i64>,
+    /// How trait images are resized to the canvas
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<Scale>,
+}
+
+impl LayerConfig {
+    /// Whether trait images of the layer may differ from the canvas size
+    pub fn is_positioned(&self) -> bool {
+        self.anchor.is_some() || self.x.is_some() || self.y.is_some() || self.scale.is_some()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Anchor {
+    #[default]
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    /// Position of an image of `size` on a canvas of `canvas`, before offsets
+    pub fn place(&self, canvas: (u32, u32), size: (u32, u32)) -> (i64, i64) {
+        let free_x = canvas.0 as i64 - size.0 as i64;
+        let free_y = canvas.1 as i64 - size.1 as i64;
+
+        let x = match self {
+            Anchor::TopLeft | Anchor::Left | Anchor::BottomLeft => 0,
+            Anchor::Top | Anchor::Center | Anchor::Bottom => free_x / 2,
+            Anchor::TopRight | Anchor::Right | Anchor::BottomRight => free_x,
+        };
+        let y = match self {
+            Anchor::TopLeft | Anchor::Top | Anchor::TopRight => 0,
+            Anchor::Left | Anchor::Center | Anchor::Right => free_y / 2,
+            Anchor::BottomLeft | Anchor::Bottom | Anchor::BottomRight => free_y,
+        };
+
+        (x, y)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scale {
+    /// Keep the image size
+    #[default]
+    None,
+    /// Resize to fit inside the canvas, keeping the aspect ratio
+    Fit,
+    /// Resize to the canvas size
+    Stretch,
+}
+
+/// Encoding of the output images
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Format {
+    Png {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        compression: Option<Compression>,
+    },
+    Jpeg {
+        /// 1 to 100, 90 by default
+        #[serde(skip_serializing_if = "Option::is_none")]
+        quality: Option<u8>,
+        /// `#rrggbb` color transparent pixels are flattened onto, white by default
+        #[serde(skip_serializing_if = "Option::is_none")]
+        background: Option<String>,
+    },
+    Webp {
+        #[serde(default)]
+        lossless: bool,
+        /// 1 to 100 when lossy, 80 by default
+        #[serde(skip_serializing_if = "Option::is_none")]
+        quality: Option<u8>,
+    },
+    Gif,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Png { compression: None }
+    }
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Png { .. } => "png",
+            Format::Jpeg { .. } => "jpg",
+            Format::Webp { .. } => "webp",
+            Format::Gif => "gif",
+        }
+    }
+
+    /// Media type written to the metadata
+    pub fn media_type(&self, animated: bool) -> &'static str {
+        match self {
+            Format::Png { .. } if animated => "image/apng",
+            Format::Png { .. } => "image/png",
+            Format::Jpeg { .. } => "image/jpeg",
+            Format::Webp { .. } => "image/webp",
+            Format::Gif => "image/gif",
+        }
+    }
+
+    pub fn can_animate(&self) -> bool {
+        matches!(self, Format::Png { .. } | Format::Gif)
+    }
+
+    /// Fails on settings the encoders can't use
+    pub fn validate(&self) -> Result<()> {
+        let quality = match self {
+            Format::Jpeg { quality, .. } | Format::Webp { quality, .. } => *quality,
+            _ => None,
+        };
+        if quality.is_some_and(|quality| !(1..=100).contains(&quality)) {
+            return Err(anyhow!("format quality must be between 1 and 100"));
+        }
+
+        if let Format::Jpeg {
+            background: Some(background),
+            ..
+        } = self
+        {
+            parse_color(background)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a `#rrggbb` color
+pub fn parse_color(color: &str) -> Result<[u8; 3]> {
+    let invalid = || anyhow!("{} is not a #rrggbb color", color);
+
+    let hex = color.strip_prefix('#').ok_or_else(invalid)?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut rgb = [0; 3];
+    for (index, channel) in rgb.iter_mut().enumerate() {
+        *channel = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+
+    Ok(rgb)
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+/// A resized copy of every token, written to `output/<config>/<name>`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OutputSize {
+    pub name: String,
+    /// Either side may be left out to keep the aspect ratio
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+}
+
+impl OutputSize {
+    /// Size of an image of `size` once resized
+    pub fn resolve(&self, size: (u32, u32)) -> (u32, u32) {
+        let (width, height) = size;
+
+        match (self.width, self.height) {
+            (Some(w), Some(h)) => (w, h),
+            (Some(w), None) => (w, ((height as u64 * w as u64) / width as u64).max(1) as u32),
+            (None, Some(h)) => (((width as u64 * h as u64) / height as u64).max(1) as u32, h),
+            (None, None) => size,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Filter {
+    /// Keeps hard pixel edges, for pixel art
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl From<Filter> for FilterType {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::Nearest => FilterType::Nearest,
+            Filter::Triangle => FilterType::Triangle,
+            Filter::CatmullRom => FilterType::CatmullRom,
+            Filter::Gaussian => FilterType::Gaussian,
+            Filter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Blend {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Add,
+    SoftLight,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct VariantConfig {
+    /// Name of the trait whose image is recolored
+    #[serde(rename = "trait")]
+    pub base: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    /// Degrees the hue of every pixel is rotated by
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hue: Option<f32>,
+    /// `#rrggbb` source colors and the colors replacing them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub palette: Option<HashMap<String, String>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct FillTrait {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    /// `#rrggbb` color, when the trait is not a gradient
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gradient: Option<Gradient>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct Gradient {
+    /// `#rrggbb` colors, spread evenly from start to end
+    pub stops: Vec<String>,
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Top to bottom
+    #[default]
+    Vertical,
+    /// Left to right
+    Horizontal,
+    /// Top left to bottom right
+    Diagonal,
 }
 
 #[derive(Deserialize, Serialize, Debug, Default, Clone)]
@@ -53,6 +432,25 @@ pub struct IfTrait {
     pub traits: Vec<String>,
 }
 
+/// Two layers whose traits are always drawn together, such as the back and the
+/// front of a hair style
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct LinkConfig {
+    pub layers: [String; 2],
+    /// Trait of the second layer per trait of the first, traits left out link
+    /// to the trait with the same name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub traits: Option<HashMap<String, String>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct RequireTrait {
+    #[serde(flatten)]
+    pub when: IfTrait,
+    /// Traits of the layer, one of them is drawn by weight when `when` matches
+    pub require: Vec<String>,
+}
+
 #[derive(Deserialize, Serialize, Debug, Default, Clone)]
 pub struct BlackList {
     pub list: Vec<BlackListLine>,
@@ -68,14 +466,14 @@ pub struct BlackListLine {
 impl AppConfig {
     pub fn load_configs(
         config_folders: &str,
-        bl_filename: &str,
+        bl_filename: Option<&str>,
         bl_case_sen: bool,
     ) -> Result<Vec<Self>> {
         let config_files = fs::read_dir(config_folders)?;
         let mut configs: Vec<Self> = vec![];
 
-        let bl = match File::open(bl_filename) {
-            Ok(mut bl_file) => {
+        let bl = match bl_filename.map(|bl_filename| (bl_filename, File::open(bl_filename))) {
+            Some((bl_filename, Ok(mut bl_file))) => {
                 let mut contents = String::new();
                 bl_file.read_to_string(&mut contents)?;
 
@@ -89,10 +487,11 @@ impl AppConfig {
 
                 Some(AppConfig::bl(parsed_bl, bl_case_sen)?)
             }
-            Err(_) => {
+            Some((_, Err(_))) => {
                 println!("No blacklist config found");
                 None
             }
+            None => None,
         };
 
         for file in config_files {
@@ -110,6 +509,9 @@ impl AppConfig {
             configs.push(parsed);
         }
 
+        // read_dir order is platform dependent, keep runs reproducible
+        configs.sort_by(|a, b| a.config_name.cmp(&b.config_name));
+
         Ok(configs)
     }
 
@@ -133,7 +535,35 @@ impl AppConfig {
         Ok(bl)
     }
 
+    /// Key of the set of DNAs this config must be unique in
+    pub fn uniqueness_scope(&self, uniqueness: Option<&Uniqueness>) -> String {
+        match uniqueness.unwrap_or(&self.uniqueness) {
+            Uniqueness::Global => String::from("global"),
+            Uniqueness::Config => format!("config:{}", self.config_name),
+            Uniqueness::Group(group) => format!("group:{}", group),
+        }
+    }
+
+    /// Layer indices that follow a link, the later layer of each link
+    pub fn link_followers(&self) -> Vec<usize> {
+        self.links
+            .iter()
+            .flatten()
+            .filter_map(|link| {
+                link.layers
+                    .iter()
+                    .filter_map(|name| self.layers.iter().position(|layer| &layer.name == name))
+                    .max()
+            })
+            .collect()
+    }
+
     pub fn is_bl(&self, traits: &HashSet<String>, bl_case_sen: bool) -> bool {
+        self.bl_hit(traits, bl_case_sen).is_some()
+    }
+
+    /// The blacklisted `(trait_name, exclude)` pair found in traits, if any
+    pub fn bl_hit(&self, traits: &HashSet<String>, bl_case_sen: bool) -> Option<(String, String)> {
         let case_traits = traits
             .iter()
             .map(|t| {
@@ -145,11 +575,16 @@ impl AppConfig {
             })
             .collect::<HashSet<String>>();
 
-        self.bl.as_ref().is_some_and(|bl| {
-            case_traits.iter().any(|t| {
-                bl.get_key_value(t)
-                    .is_some_and(|(_, v)| case_traits.contains(v))
-            })
+        self.bl.as_ref().and_then(|bl| {
+            let mut hits = case_traits
+                .iter()
+                .filter_map(|t| bl.get_key_value(t))
+                .filter(|(_, v)| case_traits.contains(*v))
+                .map(|(k, v)| (v.clone(), k.clone()))
+                .collect::<Vec<(String, String)>>();
+            // several pairs can match, always report the same one
+            hits.sort();
+            hits.into_iter().next()
         })
     }
 }
@@ -0,0 +1,219 @@
+use std::fmt;
+
+use anyhow::{anyhow, Context};
+use sha3::{Digest, Keccak256};
+
+/// Version written in front of every encoded DNA
+pub const DNA_VERSION: u32 = 1;
+
+/// The trait a DNA holds for a layer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gene {
+    pub layer_index: usize,
+    pub trait_index: usize,
+    pub layer: String,
+    pub name: String,
+}
+
+/// Canonical, decodable description of a combination
+///
+/// Encoded as `v1|<layer index>.<trait index>:<layer>=<trait>|...` in layer
+/// order, with `%`, `|`, `:` and `=` percent escaped in names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dna {
+    pub genes: Vec<Gene>,
+}
+
+impl Dna {
+    pub fn new(mut genes: Vec<Gene>) -> Self {
+        genes.sort_by_key(|gene| gene.layer_index);
+
+        Self { genes }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut code = format!("v{}", DNA_VERSION);
+
+        for gene in &self.genes {
+            code.push_str(&format!(
+                "|{}.{}:{}={}",
+                gene.layer_index,
+                gene.trait_index,
+                escape(&gene.layer),
+                escape(&gene.name)
+            ));
+        }
+
+        code
+    }
+
+    pub fn decode(code: &str) -> anyhow::Result<Self> {
+        let mut parts = code.split('|');
+
+        let version = parts
+            .next()
+            .and_then(|version| version.strip_prefix('v'))
+            .and_then(|version| version.parse::<u32>().ok())
+            .with_context(|| format!("{} does not start with a DNA version", code))?;
+        if version != DNA_VERSION {
+            return Err(anyhow!("unsupported DNA version {}", version));
+        }
+
+        let genes = parts
+            .map(|part| {
+                let malformed = || anyhow!("malformed gene {}", part);
+
+                let (indices, names) = part.split_once(':').ok_or_else(malformed)?;
+                let (layer_index, trait_index) = indices.split_once('.').ok_or_else(malformed)?;
+                let (layer, name) = names.split_once('=').ok_or_else(malformed)?;
+
+                Ok(Gene {
+                    layer_index: layer_index.parse().map_err(|_| malformed())?,
+                    trait_index: trait_index.parse().map_err(|_| malformed())?,
+                    layer: unescape(layer)?,
+                    name: unescape(name)?,
+                })
+            })
+            .collect::<anyhow::Result<Vec<Gene>>>()?;
+
+        let dna = Dna::new(genes);
+        if dna.encode() != code {
+            return Err(anyhow!("{} is not a canonical DNA", code));
+        }
+
+        Ok(dna)
+    }
+
+    /// Layer and trait name of every gene, the same for the same traits after
+    /// trait images were added or removed and indices shifted
+    pub fn names(&self) -> Vec<(String, String)> {
+        self.genes
+            .iter()
+            .map(|gene| (gene.layer.clone(), gene.name.clone()))
+            .collect()
+    }
+
+    /// Keccak256 of the encoded DNA, used for uniqueness
+    pub fn hash(&self) -> String {
+        format!("{:x}", Keccak256::digest(self.encode()))
+    }
+}
+
+impl fmt::Display for Dna {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "version {}, hash {}", DNA_VERSION, self.hash())?;
+
+        for gene in &self.genes {
+            write!(
+                f,
+                "\n  {} ({}): {} ({})",
+                gene.layer, gene.layer_index, gene.name, gene.trait_index
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn escape(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '%' | '|' | ':' | '=' => format!("%{:02X}", c as u32),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+fn unescape(name: &str) -> anyhow::Result<String> {
+    let mut unescaped = String::new();
+    let mut chars = name.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            unescaped.push(c);
+            continue;
+        }
+
+        let hex = chars.by_ref().take(2).collect::<String>();
+        let code = u8::from_str_radix(&hex, 16)
+            .with_context(|| format!("invalid escape %{} in {}", hex, name))?;
+        unescaped.push(code as char);
+    }
+
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gene(layer_index: usize, trait_index: usize, layer: &str, name: &str) -> Gene {
+        Gene {
+            layer_index,
+            trait_index,
+            layer: layer.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn encodes_genes_in_layer_order() {
+        let dna = Dna::new(vec![
+            gene(1, 0, "body", "round"),
+            gene(0, 1, "background", "clouds"),
+        ]);
+
+        assert_eq!(dna.encode(), "v1|0.1:background=clouds|1.0:body=round");
+    }
+
+    #[test]
+    fn round_trips_escaped_names() {
+        let dna = Dna::new(vec![gene(0, 2, "a|b", "50% off"), gene(1, 0, "x:y", "k=v")]);
+        let code = dna.encode();
+
+        assert_eq!(code, "v1|0.2:a%7Cb=50%25 off|1.0:x%3Ay=k%3Dv");
+        assert_eq!(Dna::decode(&code).unwrap(), dna);
+    }
+
+    #[test]
+    fn rejects_bad_escapes() {
+        assert!(Dna::decode("v1|0.0:layer=a%zz").is_err());
+        assert!(Dna::decode("v1|0.0:layer=a%2").is_err());
+    }
+
+    #[test]
+    fn rejects_non_canonical_codes() {
+        // lower case escape, unnecessary escape, genes out of order
+        assert!(Dna::decode("v1|0.0:layer=a%7cb").is_err());
+        assert!(Dna::decode("v1|0.0:layer=%61").is_err());
+        assert!(Dna::decode("v1|1.0:b=x|0.0:a=y").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        assert!(Dna::decode("0.0:layer=a").is_err());
+        assert!(Dna::decode("v2|0.0:layer=a").is_err());
+        assert!(Dna::decode("v1|0:layer=a").is_err());
+        assert!(Dna::decode("v1|0.0:layer").is_err());
+    }
+
+    #[test]
+    fn hashes_the_encoded_dna() {
+        let dna = Dna::new(vec![gene(0, 0, "layer", "a")]);
+
+        assert_eq!(
+            dna.hash(),
+            format!("{:x}", Keccak256::digest("v1|0.0:layer=a"))
+        );
+        assert_ne!(dna.hash(), Dna::new(vec![gene(0, 1, "layer", "a")]).hash());
+    }
+
+    #[test]
+    fn names_ignore_indices() {
+        let dna = Dna::new(vec![gene(0, 0, "bg", "Red"), gene(1, 2, "body", "A")]);
+        let shifted = Dna::new(vec![gene(0, 1, "bg", "Red"), gene(1, 2, "body", "A")]);
+
+        assert_ne!(dna.hash(), shifted.hash());
+        assert_eq!(dna.names(), shifted.names());
+    }
+}
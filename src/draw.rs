@@ -0,0 +1,192 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Context};
+use rand_chacha::ChaCha8Rng;
+
+use crate::{
+    config::{AppConfig, Sampling},
+    dna::Dna,
+    layers::Layers,
+    quotas::Quotas,
+    registry::{DnaRegistry, Priority},
+    rejections::{Rejection, Rejections},
+};
+
+/// Drawing state of a config across claim rounds
+///
+/// Every round each config draws candidates for its missing tokens and claims
+/// them in the registry, then keeps the claims that held once all configs are
+/// done drawing.
+pub struct Draw<'a> {
+    pub config: &'a AppConfig,
+    pub layers: &'a Layers,
+    pub amount: usize,
+    pub accepted: Vec<(Vec<usize>, Dna)>,
+    pub rejections: Rejections,
+    scope: String,
+    rng: ChaCha8Rng,
+    quotas: Quotas,
+    /// Combinations of exhaustive sampling in draw order, described once drawn
+    combinations: Option<std::vec::IntoIter<Vec<usize>>>,
+    seen: HashSet<String>,
+    pending: Vec<(Vec<usize>, Dna, String)>,
+    fail_count: usize,
+    exhausted: bool,
+}
+
+impl<'a> Draw<'a> {
+    pub fn new(
+        config: &'a AppConfig,
+        layers: &'a Layers,
+        amount: usize,
+        scope: String,
+        mut rng: ChaCha8Rng,
+        existing: HashSet<String>,
+    ) -> anyhow::Result<Self> {
+        let combinations = match config.sampling {
+            Sampling::Random => None,
+            Sampling::Exhaustive => Some(
+                layers
+                    .shuffled_combinations(&config.layers, &mut rng)
+                    .with_context(|| format!("unable to enumerate {}", config.config_name))?
+                    .into_iter(),
+            ),
+        };
+
+        Ok(Self {
+            config,
+            layers,
+            amount,
+            accepted: vec![],
+            rejections: Rejections::new(&config.config_name),
+            scope,
+            rng,
+            quotas: Quotas::new(layers, &config.layers),
+            combinations,
+            seen: existing,
+            pending: vec![],
+            fail_count: 0,
+            exhausted: false,
+        })
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.accepted.len() == self.amount || self.exhausted
+    }
+
+    /// Draws and claims candidates for the tokens that are still missing
+    pub fn draw(
+        &mut self,
+        registry: &DnaRegistry,
+        dropped: &HashSet<Vec<(String, String)>>,
+        priority: Priority,
+        bl_case_sen: bool,
+    ) {
+        while !self.exhausted && self.accepted.len() + self.pending.len() < self.amount {
+            let candidate = match self.combinations.as_mut() {
+                Some(combinations) => combinations.next().map(|combination| {
+                    let (traits, dna) = self.layers.describe(&self.config.layers, &combination);
+                    (combination, traits, dna)
+                }),
+                None => Some(self.layers.create_unique(
+                    &self.config.layers,
+                    &self.quotas,
+                    &mut self.rng,
+                )),
+            };
+            let Some((def, traits, dna)) = candidate else {
+                self.exhausted = true;
+                break;
+            };
+            let hash = dna.hash();
+
+            let rejection =
+                if let Some((trait_name, exclude)) = self.config.bl_hit(&traits, bl_case_sen) {
+                    Some(Rejection::Blacklisted(trait_name, exclude))
+                } else if self
+                    .layers
+                    .unmet_requirement(&self.config.layers, &def)
+                    .is_some()
+                {
+                    Some(Rejection::Requirement)
+                } else if !self.quotas.allows(&def) {
+                    Some(Rejection::Quota)
+                } else if self.seen.contains(&hash) {
+                    Some(Rejection::Duplicate)
+                } else if dropped.contains(&dna.names()) {
+                    Some(Rejection::Dropped)
+                } else if !registry.claim(&self.scope, &hash, priority) {
+                    Some(Rejection::Taken)
+                } else {
+                    None
+                };
+
+            match rejection {
+                Some(rejection) => self.reject(rejection),
+                None => {
+                    // held until the round settles
+                    self.quotas.take(&def);
+                    self.seen.insert(hash.clone());
+                    self.pending.push((def, dna, hash));
+                }
+            }
+        }
+    }
+
+    /// Keeps the candidates whose claim held, returns how many were kept
+    pub fn settle(&mut self, registry: &DnaRegistry, priority: Priority) -> usize {
+        let mut kept = 0;
+
+        for (def, dna, hash) in std::mem::take(&mut self.pending) {
+            if registry.owns(&self.scope, &hash, priority) {
+                self.accepted.push((def, dna));
+                self.rejections.accepted += 1;
+                kept += 1;
+            } else {
+                self.quotas.give(&def);
+                self.seen.remove(&hash);
+                self.reject(Rejection::Taken);
+            }
+        }
+
+        kept
+    }
+
+    fn reject(&mut self, rejection: Rejection) {
+        self.rejections.count(rejection);
+        self.fail_count += 1;
+
+        if self.config.sampling == Sampling::Random && self.fail_count > self.config.tolerance {
+            self.exhausted = true;
+        }
+    }
+
+    /// Why the config could not get all its tokens, if it could not
+    pub fn error(&self) -> Option<anyhow::Error> {
+        if self.accepted.len() == self.amount {
+            return None;
+        }
+
+        let reason = match self.config.sampling {
+            Sampling::Random => anyhow!(
+                "You need more features or traits to generate {}",
+                self.amount
+            ),
+            Sampling::Exhaustive => anyhow!(
+                "{} only has {} unique combinations left, unable to generate {}",
+                self.config.config_name,
+                self.accepted.len(),
+                self.amount
+            ),
+        };
+
+        if self.quotas.is_empty() {
+            return Some(reason);
+        }
+        Some(reason.context(format!(
+            "quotas of {} can't all be satisfied together\n{}",
+            self.config.config_name,
+            self.quotas.unmet(self.layers).join("\n")
+        )))
+    }
+}
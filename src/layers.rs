@@ -1,44 +1,107 @@
-use std::{collections::HashSet, ffi::OsStr, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
 
 use anyhow::{anyhow, Context};
-use image::{DynamicImage, GenericImageView};
+use image::ImageFormat;
 use rand::Rng;
-use sha3::{Digest, Keccak256};
 
-use crate::config::{AppConfig, LayerConfig};
+use crate::{
+    config::{AppConfig, IfTrait, LayerConfig, LinkConfig, Mode},
+    dna::{Dna, Gene},
+    quotas::Quotas,
+    render::{Fill, Recolor},
+    sidecar::{Sidecar, LAYER_SIDECAR},
+    utils,
+};
 
 #[derive(Debug, Clone)]
 pub struct Trait {
     pub layer: String,
     pub name: String,
     pub weight: u32,
-    pub image: Option<DynamicImage>,
+    /// Image of the trait, "None" and fill traits have none
+    pub path: Option<PathBuf>,
+    /// Drawn instead of an image for traits declared in `fills`
+    pub fill: Option<Fill>,
+    /// Applied to the image for traits declared in `variants`
+    pub recolor: Option<Recolor>,
+    /// Metadata read from the sidecar files of the trait image
+    pub sidecar: Option<Sidecar>,
+    /// Tier folder of the trait in simple mode
+    pub tier: Option<String>,
+    /// GIF and APNG traits are composited frame by frame
+    pub animated: bool,
+}
+
+impl Trait {
+    /// Whether this is the "None" trait of its layer, which draws nothing
+    pub fn is_none(&self) -> bool {
+        self.path.is_none() && self.fill.is_none()
+    }
 }
 
 pub type TraitSet = Vec<Trait>;
+/// Trait indices per layer, trait names and DNA of a drawn combination
+pub type Candidate = (Vec<usize>, HashSet<String>, Dna);
 
 #[derive(Default)]
 pub struct Layers {
     pub trait_sets: Vec<TraitSet>,
+    pub links: Vec<Link>,
     pub width: u32,
     pub height: u32,
 }
 
+/// A layer whose trait follows the trait of an earlier layer
+pub struct Link {
+    pub leader: usize,
+    pub follower: usize,
+    /// Follower trait index per leader trait index
+    pub traits: Vec<usize>,
+}
+
 const DEFAULT_WEIGHT: u32 = 50;
+/// Upper bound of raw combinations `enumerate` is willing to walk
+pub const MAX_ENUMERATION: u128 = 10_000_000;
 
 impl Layers {
     pub fn load(&mut self, config: &AppConfig) -> anyhow::Result<()> {
         let mut trait_sets = vec![];
         let mut trait_names = HashSet::new();
+        // the traits of a linked layer can share the names of their leader
+        let followers = config.link_followers();
+
+        config.format.validate()?;
+
+        let mut size_names = HashSet::new();
+        for size in config.sizes.iter().flatten() {
+            if ["image", "json", ""].contains(&size.name.as_str())
+                || size.name.contains(['/', '\\', '.'])
+                || !size_names.insert(&size.name)
+            {
+                return Err(anyhow!("{:?} can't be used as a size name", size.name));
+            }
+            if size.width == Some(0) || size.height == Some(0) {
+                return Err(anyhow!("size {} can't be 0 pixels wide or high", size.name));
+            }
+        }
 
         let layer_paths = config
             .layers
             .iter()
-            .map(|layer| (layer, config.path.join(layer.name.clone())))
-            .filter(|(_, path)| path.is_dir());
+            .enumerate()
+            .map(|(layer_idx, layer)| (layer_idx, layer, config.path.join(layer.name.clone())))
+            .filter(|(_, layer, path)| path.is_dir() || layer.fills.is_some());
 
-        for (layer_config, layer_path) in layer_paths {
+        for (layer_idx, layer_config, layer_path) in layer_paths {
             let mut trait_set: TraitSet = vec![];
+            let mut linked_names = HashSet::new();
+            let trait_names = match followers.contains(&layer_idx) {
+                true => &mut linked_names,
+                false => &mut trait_names,
+            };
 
             let layer_name = layer_config
                 .display_name
@@ -46,14 +109,71 @@ impl Layers {
                 .unwrap_or(&layer_config.name)
                 .clone();
 
-            let trait_paths = layer_path
-                .read_dir()
-                .with_context(|| format!("{} is not a folder", layer_path.display()))?
-                .map(|dir| dir.unwrap().path())
-                .filter(|path| path.is_file())
-                .filter(|path| matches!(path.extension(), Some(ext) if ext == "png"));
+            // images with their tier folder, sidecars
+            let mut trait_paths: Vec<(PathBuf, Option<String>)> = vec![];
+            let mut sidecar_paths = vec![];
+            // a layer of fills only needs no folder
+            if layer_path.is_dir() {
+                let (images, sidecars) = list_folder(&layer_path, layer_config)?;
+                sidecar_paths.extend(sidecars);
+
+                match config.mode {
+                    Mode::Advanced => {
+                        trait_paths.extend(images.into_iter().map(|path| (path, None)))
+                    }
+                    Mode::Simple => {
+                        if let Some(image) = images.first() {
+                            return Err(anyhow!(
+                                "{} is not in a tier folder, simple mode needs {}/<tier>/<trait>",
+                                image.display(),
+                                layer_path.display()
+                            ));
+                        }
+
+                        for tier_path in list_tiers(&layer_path)? {
+                            let tier = tier_path
+                                .file_name()
+                                .and_then(|name| name.to_str())
+                                .unwrap_or_default()
+                                .to_string();
+                            if !config.tiers.as_ref().is_some_and(|t| t.contains_key(&tier)) {
+                                return Err(anyhow!(
+                                    "{} has no weight, add {} to tiers",
+                                    tier_path.display(),
+                                    tier
+                                ));
+                            }
+
+                            let (images, sidecars) = list_folder(&tier_path, layer_config)?;
+                            sidecar_paths.extend(sidecars);
+                            trait_paths
+                                .extend(images.into_iter().map(|path| (path, Some(tier.clone()))));
+                        }
+                    }
+                }
+            }
+            // read_dir order is platform dependent, keep trait indices stable
+            trait_paths.sort();
+
+            let mut layer_sidecars = match layer_path.join(LAYER_SIDECAR) {
+                path if path.is_file() => Sidecar::load_layer(&path)?,
+                _ => HashMap::new(),
+            };
+            let mut conflicts = vec![];
 
-            for trait_path in trait_paths {
+            for sidecar_path in &sidecar_paths {
+                let has_image = trait_paths
+                    .iter()
+                    .any(|(path, _)| path.with_extension("json") == *sidecar_path);
+                if !has_image && *sidecar_path != layer_path.join(LAYER_SIDECAR) {
+                    return Err(anyhow!(
+                        "{} has no trait image next to it",
+                        sidecar_path.display()
+                    ));
+                }
+            }
+
+            for (trait_path, tier) in trait_paths {
                 let file_name = trait_path
                     .file_stem()
                     .unwrap()
@@ -61,52 +181,201 @@ impl Layers {
                     .unwrap()
                     .to_string();
 
+                let sidecar_path = trait_path.with_extension("json");
+                let mut sidecar = match sidecar_path.is_file() {
+                    true => Sidecar::load(&sidecar_path)?,
+                    false => Sidecar::default(),
+                };
+                if let Some(layer_sidecar) = layer_sidecars.remove(&file_name) {
+                    sidecar = sidecar.merge(
+                        layer_sidecar,
+                        &sidecar_path.display().to_string(),
+                        &mut conflicts,
+                    );
+                }
+
+                // the file name convention is name#WEIGHT
+                let (name, weight) = match file_name.rsplit_once('#') {
+                    Some((name, weight)) => match weight.parse::<u32>() {
+                        Ok(weight) => (name.to_owned(), Some(weight)),
+                        // the sidecar names traits whose name has a #
+                        Err(_) if sidecar.name.is_some() => (file_name.clone(), None),
+                        Err(_) => {
+                            return Err(anyhow!(format!("{} is not a parsable number", weight)))
+                        }
+                    },
+                    None => (file_name.clone(), None),
+                };
+                if tier.is_some() && (weight.is_some() || sidecar.weight.is_some()) {
+                    conflicts.push(format!(
+                        "{}: weights come from tiers in simple mode",
+                        trait_path.display()
+                    ));
+                }
+                if let (Some(weight), Some(sidecar_weight)) = (weight, sidecar.weight) {
+                    if weight != sidecar_weight {
+                        conflicts.push(format!(
+                            "{}: weight is {} in the file name but {} in the sidecar",
+                            trait_path.display(),
+                            weight,
+                            sidecar_weight
+                        ));
+                    }
+                }
+
+                // a file name can't hold a name with a #, so the sidecar may
+                // rename it then, any other difference is a conflict
+                let name = match &sidecar.name {
+                    Some(sidecar_name) if sidecar_name != &name && !sidecar_name.contains('#') => {
+                        conflicts.push(format!(
+                            "{}: name is {} in the file name but {} in the sidecar",
+                            trait_path.display(),
+                            name,
+                            sidecar_name
+                        ));
+                        name
+                    }
+                    Some(sidecar_name) => sidecar_name.clone(),
+                    None => name,
+                };
+
                 match &config.off_traits {
-                    Some(off) if off.contains(&file_name) => {
+                    Some(off) if off.contains(&name) => {
                         continue;
                     }
                     _ => {}
                 }
 
-                let image = image::open(&trait_path)
+                // only the header is read, images are decoded when rendering
+                let (width, height) = image::image_dimensions(&trait_path)
                     .with_context(|| format!("failed to load image {}", trait_path.display()))?;
 
-                let (width, height) = image.dimensions();
-
                 if self.width == 0 && self.height == 0 {
-                    self.width = width;
-                    self.height = height;
+                    self.width = config.width.unwrap_or(width);
+                    self.height = config.height.unwrap_or(height);
                 }
 
-                if file_name.contains('#') {
-                    let parts: Vec<&str> = file_name.split('#').collect();
+                if (width, height) != (self.width, self.height) && !layer_config.is_positioned() {
+                    return Err(anyhow!(
+                        "{} is {}x{} but the canvas is {}x{}, set anchor, x, y or scale on layer {} to place it",
+                        trait_path.display(),
+                        width,
+                        height,
+                        self.width,
+                        self.height,
+                        layer_config.name
+                    ));
+                }
 
-                    let name = parts[0];
+                let animated = utils::animation(&trait_path)?.is_some();
+                if animated && !config.format.can_animate() {
+                    return Err(anyhow!(
+                        "{} is animated but {} output can't be, use png or gif",
+                        trait_path.display(),
+                        config.format.extension()
+                    ));
+                }
 
-                    let weight = parts[1]
-                        .parse()
-                        .with_context(|| format!("{} is not a parsable number", parts[1]))?;
+                if !trait_names.insert(name.clone()) {
+                    return Err(anyhow!(
+                        "Duplicated trait name of {} ({})",
+                        name,
+                        trait_path.display()
+                    ));
+                }
 
-                    trait_set.push(Trait {
-                        layer: layer_name.clone(),
-                        name: name.to_owned(),
-                        image: Some(image),
-                        weight,
-                    })
-                } else {
-                    trait_set.push(Trait {
-                        layer: layer_name.clone(),
-                        name: file_name.clone(),
-                        image: Some(image),
-                        weight: DEFAULT_WEIGHT,
-                    })
+                trait_set.push(Trait {
+                    layer: layer_name.clone(),
+                    name,
+                    path: Some(trait_path.clone()),
+                    fill: None,
+                    recolor: None,
+                    weight: weight.or(sidecar.weight).unwrap_or(DEFAULT_WEIGHT),
+                    animated,
+                    sidecar: Some(sidecar),
+                    tier,
+                });
+            }
+
+            for variant in layer_config.variants.iter().flatten() {
+                match &config.off_traits {
+                    Some(off) if off.contains(&variant.name) => {
+                        continue;
+                    }
+                    _ => {}
                 }
 
-                if !trait_names.insert(file_name.clone()) {
-                    return Err(anyhow!(format!("Duplicated trait name of {}", file_name)));
+                let base = trait_set
+                    .iter()
+                    .find(|elem| elem.path.is_some() && elem.name == variant.base)
+                    .with_context(|| {
+                        format!(
+                            "variant {} recolors {} but layer {} has no such trait image",
+                            variant.name, variant.base, layer_config.name
+                        )
+                    })?
+                    .clone();
+
+                trait_set.push(Trait {
+                    name: variant.name.clone(),
+                    weight: variant.weight.unwrap_or(DEFAULT_WEIGHT),
+                    recolor: Some(Recolor::new(variant)?),
+                    // the display name and attributes are the base trait's
+                    sidecar: None,
+                    tier: None,
+                    ..base
+                });
+
+                if !trait_names.insert(variant.name.clone()) {
+                    return Err(anyhow!(format!(
+                        "Duplicated trait name of {}",
+                        variant.name
+                    )));
                 }
             }
 
+            for fill in layer_config.fills.iter().flatten() {
+                match &config.off_traits {
+                    Some(off) if off.contains(&fill.name) => {
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                trait_set.push(Trait {
+                    layer: layer_name.clone(),
+                    name: fill.name.clone(),
+                    weight: fill.weight.unwrap_or(DEFAULT_WEIGHT),
+                    path: None,
+                    fill: Some(Fill::new(fill)?),
+                    recolor: None,
+                    animated: false,
+                    sidecar: None,
+                    tier: None,
+                });
+
+                if !trait_names.insert(fill.name.clone()) {
+                    return Err(anyhow!(format!("Duplicated trait name of {}", fill.name)));
+                }
+            }
+
+            let mut unknown = layer_sidecars.into_keys().collect::<Vec<String>>();
+            unknown.sort();
+            for stem in unknown {
+                conflicts.push(format!(
+                    "{}: {} has no trait image",
+                    layer_path.join(LAYER_SIDECAR).display(),
+                    stem
+                ));
+            }
+            if !conflicts.is_empty() {
+                return Err(anyhow!(
+                    "conflicting trait metadata in layer {}\n{}",
+                    layer_config.name,
+                    conflicts.join("\n")
+                ));
+            }
+
             let mut already_has_none = false;
 
             if let Some(weight) = layer_config.none {
@@ -114,7 +383,12 @@ impl Layers {
                     layer: layer_name.clone(),
                     name: "None".to_string(),
                     weight,
-                    image: None,
+                    path: None,
+                    fill: None,
+                    recolor: None,
+                    animated: false,
+                    sidecar: None,
+                    tier: None,
                 });
 
                 already_has_none = true;
@@ -125,72 +399,194 @@ impl Layers {
                     layer: layer_name,
                     name: "None".to_string(),
                     weight: 0,
-                    image: None,
+                    path: None,
+                    fill: None,
+                    recolor: None,
+                    animated: false,
+                    sidecar: None,
+                    tier: None,
                 });
             }
 
+            for rule in layer_config.require_if_traits.iter().flatten() {
+                // requirements are drawn in layer order, they can only follow
+                // the layers above
+                if !rule.when.layer.is_empty()
+                    && !trait_sets
+                        .iter()
+                        .flatten()
+                        .any(|elem: &Trait| elem.layer == rule.when.layer)
+                {
+                    return Err(anyhow!(
+                        "require_if_traits of layer {} can only match a layer before it, not {}",
+                        layer_config.name,
+                        rule.when.layer
+                    ));
+                }
+                if let Some(name) = rule
+                    .require
+                    .iter()
+                    .find(|name| !trait_set.iter().any(|elem| &elem.name == *name))
+                {
+                    return Err(anyhow!(
+                        "layer {} has no trait {} to require",
+                        layer_config.name,
+                        name
+                    ));
+                }
+                if !trait_set
+                    .iter()
+                    .any(|elem| elem.weight > 0 && rule.require.contains(&elem.name))
+                {
+                    return Err(anyhow!(
+                        "require_if_traits of layer {} needs a required trait with a weight",
+                        layer_config.name
+                    ));
+                }
+            }
+
+            if layer_config
+                .opacity
+                .is_some_and(|opacity| !(0.0..=1.0).contains(&opacity))
+            {
+                return Err(anyhow!(
+                    "opacity of layer {} must be between 0 and 1",
+                    layer_config.name
+                ));
+            }
+
+            if config.mode == Mode::Simple {
+                apply_tiers(
+                    &mut trait_set,
+                    config.tiers.as_ref().unwrap_or(&HashMap::new()),
+                )
+                .with_context(|| format!("unable to weigh layer {}", layer_config.name))?;
+            }
+
+            if layer_config.quota.unwrap_or(false) {
+                if config.mode == Mode::Simple {
+                    return Err(anyhow!(
+                        "layer {} can't have quotas in simple mode",
+                        layer_config.name
+                    ));
+                }
+
+                let total = trait_set
+                    .iter()
+                    .fold(0, |acc, elem| acc + elem.weight as usize);
+                if total != config.amount {
+                    return Err(anyhow!(
+                        "quotas of layer {} sum to {} but amount is {}",
+                        layer_config.name,
+                        total,
+                        config.amount
+                    ));
+                }
+            }
+
             trait_sets.push(trait_set);
         }
 
+        if self.width == 0 || self.height == 0 {
+            self.width = config.width.unwrap_or(self.width);
+            self.height = config.height.unwrap_or(self.height);
+        }
+        if (self.width == 0 || self.height == 0)
+            && trait_sets.iter().flatten().any(|elem| elem.fill.is_some())
+        {
+            return Err(anyhow!(
+                "fills need a canvas size, set width and height in the config"
+            ));
+        }
+
+        let mut links = config
+            .links
+            .iter()
+            .flatten()
+            .map(|link| resolve_link(config, &trait_sets, link))
+            .collect::<anyhow::Result<Vec<Link>>>()?;
+        // chained links resolve in layer order
+        links.sort_by_key(|link| link.follower);
+        for pair in links.windows(2) {
+            if pair[0].follower == pair[1].follower {
+                return Err(anyhow!(
+                    "layer {} can only follow one link",
+                    config.layers[pair[0].follower].name
+                ));
+            }
+        }
+
         self.trait_sets = trait_sets;
+        self.links = links;
 
         Ok(())
     }
 
-    pub fn create_unique(
+    pub fn create_unique<R: Rng>(
         &self,
         layer_cfgs: &[LayerConfig],
-    ) -> (Vec<usize>, HashSet<String>, String) {
+        quotas: &Quotas,
+        rng: &mut R,
+    ) -> Candidate {
         let mut random = Vec::new();
-        let mut rng = rand::thread_rng();
-        let mut trait_names = HashSet::new();
 
         // create rnd layers
-        for (trait_list, layer_config) in self.trait_sets.iter().zip(layer_cfgs) {
-            let total_weight = trait_list.iter().fold(0, |acc, elem| acc + elem.weight);
+        for (layer_idx, trait_list) in self.trait_sets.iter().enumerate() {
+            if let Some(link) = self.link_of(layer_idx) {
+                random.push(link.traits[random[link.leader]]);
+                continue;
+            }
+
+            let required = self.required(layer_cfgs, layer_idx, &random);
+            let allowed = |index: &usize| required.as_ref().is_none_or(|r| r.contains(index));
+
+            let total_weight = trait_list
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| allowed(index))
+                .fold(0, |acc, (index, elem)| {
+                    acc + quotas.weight(layer_idx, index, elem.weight)
+                });
             let random_num = rng.gen_range(0.0..1.0);
             let mut n = (random_num * total_weight as f64).floor();
 
             for (index, elem) in trait_list.iter().enumerate() {
-                n -= elem.weight as f64;
+                if !allowed(&index) {
+                    continue;
+                }
+                n -= quotas.weight(layer_idx, index, elem.weight) as f64;
 
                 if n < 0.0 {
                     random.push(index);
-                    trait_names.insert((layer_config.name.clone(), elem.name.clone()));
                     break;
                 }
             }
+
+            // every allowed trait is out of quota, the quota check rejects it
+            if random.len() == layer_idx {
+                random.push(required.map_or(0, |required| required[0]));
+            }
         }
 
-        // apply excludes
+        self.apply_excludes(layer_cfgs, &mut random);
+        self.apply_links(&mut random);
+
+        // everything is derived from what is actually rendered
+        let (trait_names, dna) = self.describe(layer_cfgs, &random);
+
+        (random, trait_names, dna)
+    }
+
+    /// Turns layers to "None" when their `exclude_if_traits` match
+    fn apply_excludes(&self, layer_cfgs: &[LayerConfig], random: &mut [usize]) {
         for layer_idx in 0..random.len() {
             let exuclude_trait = layer_cfgs[layer_idx]
                 .exclude_if_traits
                 .as_ref()
                 .is_some_and(|if_traits| {
-                    if_traits.iter().any(|if_trait| {
-                        // search through previously applied layers for a match
-                        random.iter().enumerate().any(|(i_layer_idx, trait_idx)| {
-                            let bucket = &self.trait_sets[i_layer_idx];
-                            let nft_trait: &Trait = &bucket[*trait_idx];
-
-                            // if filter only contains layer exclude that layer
-                            if if_trait.traits.is_empty() {
-                                return nft_trait.layer == if_trait.layer
-                                    && nft_trait.image.is_some();
-                            }
-                            if if_trait.layer.is_empty() {
-                                return if_trait
-                                    .traits
-                                    .iter()
-                                    .any(|t| t == &nft_trait.name && nft_trait.image.is_some());
-                            }
-
-                            // if filter contains both, both must be match
-                            nft_trait.layer == if_trait.layer
-                                && if_trait.traits.iter().any(|t| t == &nft_trait.name)
-                        })
-                    })
+                    if_traits
+                        .iter()
+                        .any(|if_trait| self.matches(if_trait, random))
                 });
 
             if exuclude_trait {
@@ -198,29 +594,788 @@ impl Layers {
                 random[layer_idx] = self.trait_sets[layer_idx].len() - 1;
             };
         }
+    }
+
+    /// First layer of a combination, as rendered, that is not one of the traits
+    /// its requirement pins it to, with those traits
+    ///
+    /// Requirements pin the draw, but excludes run after it and can turn a
+    /// pinned layer to "None", such combinations are not allowed.
+    pub fn unmet_requirement(
+        &self,
+        layer_cfgs: &[LayerConfig],
+        combination: &[usize],
+    ) -> Option<(usize, Vec<usize>)> {
+        (0..combination.len()).find_map(|layer_idx| {
+            self.required(layer_cfgs, layer_idx, combination)
+                .filter(|required| !required.contains(&combination[layer_idx]))
+                .map(|required| (layer_idx, required))
+        })
+    }
+
+    /// Gives the followers of links the trait linked to their leader, after
+    /// excludes changed the leader
+    fn apply_links(&self, combination: &mut [usize]) {
+        for link in &self.links {
+            combination[link.follower] = link.traits[combination[link.leader]];
+        }
+    }
+
+    /// The link a layer follows
+    pub fn link_of(&self, layer_idx: usize) -> Option<&Link> {
+        self.links.iter().find(|link| link.follower == layer_idx)
+    }
+
+    /// Trait indices a layer is pinned to by the first of its
+    /// `require_if_traits` that matches the layers before it
+    fn required(
+        &self,
+        layer_cfgs: &[LayerConfig],
+        layer_idx: usize,
+        earlier: &[usize],
+    ) -> Option<Vec<usize>> {
+        let rule = layer_cfgs[layer_idx]
+            .require_if_traits
+            .as_ref()?
+            .iter()
+            .find(|rule| self.matches(&rule.when, &earlier[..layer_idx]))?;
+
+        Some(
+            self.trait_sets[layer_idx]
+                .iter()
+                .enumerate()
+                .filter(|(_, elem)| rule.require.contains(&elem.name))
+                .map(|(index, _)| index)
+                .collect(),
+        )
+    }
+
+    /// Whether a trait of a combination matches the filter
+    fn matches(&self, if_trait: &IfTrait, combination: &[usize]) -> bool {
+        // search through previously applied layers for a match
+        combination
+            .iter()
+            .enumerate()
+            .any(|(i_layer_idx, trait_idx)| {
+                let bucket = &self.trait_sets[i_layer_idx];
+                let nft_trait: &Trait = &bucket[*trait_idx];
+
+                // if filter only contains layer exclude that layer
+                if if_trait.traits.is_empty() {
+                    return nft_trait.layer == if_trait.layer && !nft_trait.is_none();
+                }
+                if if_trait.layer.is_empty() {
+                    return if_trait
+                        .traits
+                        .iter()
+                        .any(|t| t == &nft_trait.name && !nft_trait.is_none());
+                }
+
+                // if filter contains both, both must be match
+                nft_trait.layer == if_trait.layer
+                    && if_trait.traits.iter().any(|t| t == &nft_trait.name)
+            })
+    }
+
+    /// Whether a combination has an animated trait, it is then rendered as an
+    /// animation
+    pub fn is_animated(&self, combination: &[usize]) -> bool {
+        combination
+            .iter()
+            .zip(&self.trait_sets)
+            .any(|(index, trait_list)| trait_list[*index].animated)
+    }
+
+    /// Trait names and DNA of a combination as it will be rendered
+    pub fn describe(
+        &self,
+        layer_cfgs: &[LayerConfig],
+        combination: &[usize],
+    ) -> (HashSet<String>, Dna) {
+        let genes = combination
+            .iter()
+            .zip(&self.trait_sets)
+            .zip(layer_cfgs)
+            .enumerate()
+            .map(|(layer_idx, ((index, trait_list), layer_config))| Gene {
+                layer_index: layer_idx,
+                trait_index: *index,
+                layer: layer_config.name.clone(),
+                name: trait_list[*index].name.clone(),
+            })
+            .collect::<Vec<Gene>>();
 
         (
-            random,
-            trait_names.iter().map(|(_, t)| t.clone()).collect(),
-            Layers::hash_dna(
-                trait_names
-                    .iter()
-                    .map(|(l, t)| format!("{}-{}", l, t))
-                    .collect(),
-            ),
+            genes.iter().map(|gene| gene.name.clone()).collect(),
+            Dna::new(genes),
         )
     }
 
-    fn hash_dna(traits: HashSet<String>) -> String {
-        let mut sorted: Vec<Vec<u8>> = traits
+    /// Layer indices and trait indices of named traits, layers that are not
+    /// named get "None"
+    pub fn lookup(
+        &self,
+        layer_cfgs: &[LayerConfig],
+        traits: &[(String, String)],
+    ) -> anyhow::Result<Vec<usize>> {
+        for (layer, _) in traits {
+            if !layer_cfgs
+                .iter()
+                .any(|layer_config| &layer_config.name == layer)
+            {
+                return Err(anyhow!("unknown layer {}", layer));
+            }
+        }
+
+        let combination = self
+            .trait_sets
+            .iter()
+            .zip(layer_cfgs)
+            .map(|(trait_list, layer_config)| {
+                let name = traits
+                    .iter()
+                    .find(|(layer, _)| layer == &layer_config.name)
+                    .map_or("None", |(_, name)| name.as_str());
+
+                trait_list
+                    .iter()
+                    .position(|elem| elem.name == name)
+                    .with_context(|| format!("layer {} has no trait {}", layer_config.name, name))
+            })
+            .collect::<anyhow::Result<Vec<usize>>>()?;
+
+        // a combination no draw can produce
+        if let Some((layer_idx, required)) = self.unmet_requirement(layer_cfgs, &combination) {
+            return Err(anyhow!(
+                "layer {} must be one of {} with these traits",
+                layer_cfgs[layer_idx].name,
+                required
+                    .iter()
+                    .map(|index| self.trait_sets[layer_idx][*index].name.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            ));
+        }
+
+        for link in &self.links {
+            let linked = link.traits[combination[link.leader]];
+            if combination[link.follower] != linked {
+                return Err(anyhow!(
+                    "layer {} must be {} with {}",
+                    layer_cfgs[link.follower].name,
+                    self.trait_sets[link.follower][linked].name,
+                    self.trait_sets[link.leader][combination[link.leader]].name
+                ));
+            }
+        }
+
+        Ok(combination)
+    }
+
+    /// Whether excludes or requirements can rule out or merge combinations
+    pub fn has_rules(&self, layer_cfgs: &[LayerConfig]) -> bool {
+        layer_cfgs.iter().any(|layer_config| {
+            layer_config.exclude_if_traits.is_some() || layer_config.require_if_traits.is_some()
+        })
+    }
+
+    /// Number of combinations that can be drawn, before excludes and blacklist
+    pub fn raw_combinations(&self) -> u128 {
+        self.drawable()
+            .iter()
+            .fold(1u128, |acc, layer| acc.saturating_mul(layer.len() as u128))
+    }
+
+    /// Drawable trait indices per layer with their probability
+    fn drawable(&self) -> Vec<Vec<(usize, f64)>> {
+        // traits with no weight are only reachable through excludes
+        self.trait_sets
+            .iter()
+            .enumerate()
+            .map(|(layer_idx, trait_list)| {
+                // followers have a single choice, the one of their leader
+                if self.link_of(layer_idx).is_some() {
+                    return vec![(0, 1.0)];
+                }
+
+                let total_weight = trait_list.iter().fold(0, |acc, elem| acc + elem.weight);
+                trait_list
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, elem)| elem.weight > 0)
+                    .map(|(index, elem)| (index, elem.weight as f64 / total_weight as f64))
+                    .collect::<Vec<(usize, f64)>>()
+            })
+            .collect()
+    }
+
+    /// Every distinct combination that can be rendered, after excludes, with
+    /// the probability of drawing it
+    pub fn enumerate(&self, layer_cfgs: &[LayerConfig]) -> anyhow::Result<Vec<(Vec<usize>, f64)>> {
+        let candidates = self.drawable();
+
+        let raw = self.raw_combinations();
+        if raw > MAX_ENUMERATION {
+            return Err(anyhow!(
+                "{} raw combinations is too many to enumerate, the limit is {}",
+                raw,
+                MAX_ENUMERATION
+            ));
+        }
+        if raw == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut combinations: HashMap<Vec<usize>, f64> = HashMap::new();
+        let mut odometer = vec![0; candidates.len()];
+
+        loop {
+            let mut combination = Vec::with_capacity(candidates.len());
+            let mut probability = 1.0;
+            for (layer_idx, (layer, position)) in candidates.iter().zip(&odometer).enumerate() {
+                let (index, p) = layer[*position];
+                let index = self
+                    .link_of(layer_idx)
+                    .map_or(index, |link| link.traits[combination[link.leader]]);
+                // a pinned layer is drawn from its required traits only
+                let p = match self.required(layer_cfgs, layer_idx, &combination) {
+                    Some(required) => {
+                        let trait_list = &self.trait_sets[layer_idx];
+                        let total_weight = required
+                            .iter()
+                            .fold(0, |acc, index| acc + trait_list[*index].weight);
+                        match required.contains(&index) {
+                            true => trait_list[index].weight as f64 / total_weight as f64,
+                            false => 0.0,
+                        }
+                    }
+                    None => p,
+                };
+                combination.push(index);
+                probability *= p;
+            }
+
+            if probability > 0.0 {
+                self.apply_excludes(layer_cfgs, &mut combination);
+                self.apply_links(&mut combination);
+                if self.unmet_requirement(layer_cfgs, &combination).is_none() {
+                    *combinations.entry(combination).or_default() += probability;
+                }
+            }
+
+            // advance to the next raw combination
+            let mut layer_idx = odometer.len();
+            loop {
+                if layer_idx == 0 {
+                    let mut combinations = combinations.into_iter().collect::<Vec<_>>();
+                    combinations.sort_by(|a, b| a.0.cmp(&b.0));
+                    return Ok(combinations);
+                }
+                layer_idx -= 1;
+                odometer[layer_idx] += 1;
+                if odometer[layer_idx] < candidates[layer_idx].len() {
+                    break;
+                }
+                odometer[layer_idx] = 0;
+            }
+        }
+    }
+
+    /// All distinct combinations in a weighted random order, drawing from the
+    /// front of it samples without replacement
+    pub fn shuffled_combinations<R: Rng>(
+        &self,
+        layer_cfgs: &[LayerConfig],
+        rng: &mut R,
+    ) -> anyhow::Result<Vec<Vec<usize>>> {
+        // Efraimidis-Spirakis: sort by u^(1/w), compared in log space
+        let mut keyed = self
+            .enumerate(layer_cfgs)?
+            .into_iter()
+            .map(|(combination, p)| {
+                let u: f64 = rng.gen_range(f64::MIN_POSITIVE..1.0);
+                (u.ln() / p, combination)
+            })
+            .collect::<Vec<(f64, Vec<usize>)>>();
+        keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        // described by the draw as they are taken, most are never needed
+        Ok(keyed
+            .into_iter()
+            .map(|(_, combination)| combination)
+            .collect())
+    }
+}
+
+/// Trait indices of the later layer of a link per trait of the earlier one,
+/// the earlier layer is drawn and the later one follows it
+fn resolve_link(
+    config: &AppConfig,
+    trait_sets: &[TraitSet],
+    link: &LinkConfig,
+) -> anyhow::Result<Link> {
+    let position = |name: &String| {
+        config
+            .layers
+            .iter()
+            .position(|layer_config| &layer_config.name == name)
+            .filter(|layer_idx| *layer_idx < trait_sets.len())
+            .with_context(|| format!("unknown linked layer {}", name))
+    };
+    let (first, second) = (position(&link.layers[0])?, position(&link.layers[1])?);
+    if first == second {
+        return Err(anyhow!(
+            "layer {} can't be linked to itself",
+            link.layers[0]
+        ));
+    }
+
+    let names = link.traits.clone().unwrap_or_default();
+    let (leader, follower, names) = match first < second {
+        true => (first, second, names),
+        false => (
+            second,
+            first,
+            names.into_iter().map(|(from, to)| (to, from)).collect(),
+        ),
+    };
+    if let Some(name) = names
+        .keys()
+        .find(|name| !trait_sets[leader].iter().any(|elem| &elem.name == *name))
+    {
+        return Err(anyhow!(
+            "layer {} has no trait {} to link",
+            config.layers[leader].name,
+            name
+        ));
+    }
+    // the follower is overwritten by the link, its own rules would be ignored
+    let follower_config = &config.layers[follower];
+    if let Some(rule) = [
+        (
+            "require_if_traits",
+            follower_config.require_if_traits.is_some(),
+        ),
+        (
+            "exclude_if_traits",
+            follower_config.exclude_if_traits.is_some(),
+        ),
+        ("quota", follower_config.quota.unwrap_or(false)),
+    ]
+    .iter()
+    .find_map(|(rule, is_set)| is_set.then_some(rule))
+    {
+        return Err(anyhow!(
+            "layer {} follows a link and can't have {}",
+            follower_config.name,
+            rule
+        ));
+    }
+
+    let traits = trait_sets[leader]
+        .iter()
+        .map(|elem| {
+            let name = names.get(&elem.name).unwrap_or(&elem.name);
+            trait_sets[follower]
+                .iter()
+                .position(|linked| &linked.name == name)
+                .with_context(|| {
+                    format!(
+                        "layer {} has no trait {} to link to {} of layer {}",
+                        config.layers[follower].name, name, elem.name, config.layers[leader].name
+                    )
+                })
+        })
+        .collect::<anyhow::Result<Vec<usize>>>()?;
+
+    Ok(Link {
+        leader,
+        follower,
+        traits,
+    })
+}
+
+/// Trait images and sidecars of a folder
+fn list_folder(
+    folder: &Path,
+    layer_config: &LayerConfig,
+) -> anyhow::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut images = vec![];
+    let mut sidecars = vec![];
+
+    for path in folder
+        .read_dir()
+        .with_context(|| format!("{} is not a folder", folder.display()))?
+        .map(|dir| dir.unwrap().path())
+        .filter(|path| path.is_file())
+    {
+        // e.g. .DS_Store
+        if is_hidden(&path) {
+            println!("Skipping hidden file {}", path.display());
+            continue;
+        }
+
+        if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+        {
+            sidecars.push(path);
+            continue;
+        }
+
+        if !ImageFormat::from_path(&path).is_ok_and(|format| format.can_read()) {
+            return Err(anyhow!(
+                "{} is not a supported image, remove it from layer {}",
+                path.display(),
+                layer_config.name
+            ));
+        }
+
+        images.push(path);
+    }
+
+    Ok((images, sidecars))
+}
+
+/// Tier folders of a layer folder in simple mode
+fn list_tiers(folder: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut tiers = folder
+        .read_dir()
+        .with_context(|| format!("{} is not a folder", folder.display()))?
+        .map(|dir| dir.unwrap().path())
+        .filter(|path| path.is_dir() && !is_hidden(path))
+        .collect::<Vec<PathBuf>>();
+    tiers.sort();
+
+    Ok(tiers)
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.'))
+}
+
+/// Splits the weight of every tier evenly between its traits
+///
+/// Weights are integers, so every weight of the layer is scaled by the least
+/// common multiple of the tier sizes to keep the split exact.
+fn apply_tiers(trait_set: &mut [Trait], tiers: &HashMap<String, u32>) -> anyhow::Result<()> {
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    for elem in trait_set.iter() {
+        if let Some(tier) = &elem.tier {
+            *counts.entry(tier.clone()).or_default() += 1;
+        }
+    }
+
+    let scale = counts
+        .values()
+        .fold(1, |lcm, count| lcm / utils::gcd(lcm, *count) * count);
+
+    for elem in trait_set.iter_mut() {
+        let weight = match &elem.tier {
+            Some(tier) => tiers[tier] as u64 * scale / counts[tier],
+            None => elem.weight as u64 * scale,
+        };
+        elem.weight = u32::try_from(weight)
+            .map_err(|_| anyhow!("weight of {} is too large once split", elem.name))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::RequireTrait;
+
+    /// Layers of `(name, weight)` traits, "None" traits draw nothing
+    pub(crate) fn layers(sets: &[(&str, &[(&str, u32)])]) -> Layers {
+        Layers {
+            trait_sets: sets
+                .iter()
+                .map(|(layer, traits)| {
+                    traits
+                        .iter()
+                        .map(|(name, weight)| Trait {
+                            layer: layer.to_string(),
+                            name: name.to_string(),
+                            weight: *weight,
+                            path: (*name != "None")
+                                .then(|| PathBuf::from(format!("{}/{}.png", layer, name))),
+                            fill: None,
+                            recolor: None,
+                            sidecar: None,
+                            tier: None,
+                            animated: false,
+                        })
+                        .collect()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn layer_cfgs(names: &[&str]) -> Vec<LayerConfig> {
+        names
+            .iter()
+            .map(|name| LayerConfig {
+                name: name.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_draws_the_same_combinations() {
+        let layers = layers(&[
+            ("bg", &[("red", 10), ("blue", 20), ("green", 30)]),
+            ("body", &[("a", 5), ("b", 50)]),
+        ]);
+        let cfgs = layer_cfgs(&["bg", "body"]);
+        let quotas = Quotas::new(&layers, &cfgs);
+        let draw = |seed, config_name| {
+            let mut rng = utils::config_rng(seed, config_name);
+            (0..20)
+                .map(|_| layers.create_unique(&cfgs, &quotas, &mut rng).0)
+                .collect::<Vec<Vec<usize>>>()
+        };
+
+        assert_eq!(draw(42, "bears"), draw(42, "bears"));
+        assert_ne!(draw(42, "bears"), draw(43, "bears"));
+        assert_ne!(draw(42, "bears"), draw(42, "ladies"));
+    }
+
+    #[test]
+    fn enumerates_distinct_combinations_with_their_odds() {
+        let layers = layers(&[
+            ("bg", &[("red", 1), ("blue", 3)]),
+            ("body", &[("a", 1), ("b", 1), ("ghost", 0)]),
+        ]);
+        let cfgs = layer_cfgs(&["bg", "body"]);
+
+        let combinations = layers.enumerate(&cfgs).unwrap();
+
+        // traits without weight are never drawn
+        assert_eq!(
+            combinations
+                .iter()
+                .map(|(combination, _)| combination.clone())
+                .collect::<Vec<Vec<usize>>>(),
+            vec![vec![0, 0], vec![0, 1], vec![1, 0], vec![1, 1]]
+        );
+        assert!((combinations[2].1 - 0.375).abs() < 1e-9);
+        let total = combinations.iter().map(|(_, p)| p).sum::<f64>();
+        assert!((total - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn shuffles_every_combination_once_favoring_weights() {
+        let layers = layers(&[
+            ("bg", &[("rare", 1), ("common", 99)]),
+            ("body", &[("a", 1), ("b", 1)]),
+        ]);
+        let cfgs = layer_cfgs(&["bg", "body"]);
+
+        let mut common_first = 0;
+        for seed in 0..100 {
+            let mut rng = utils::config_rng(seed, "bears");
+            let mut shuffled = layers.shuffled_combinations(&cfgs, &mut rng).unwrap();
+            if shuffled[0][0] == 1 {
+                common_first += 1;
+            }
+
+            shuffled.sort();
+            assert_eq!(
+                shuffled,
+                vec![vec![0, 0], vec![0, 1], vec![1, 0], vec![1, 1]]
+            );
+        }
+        assert!(common_first > 90);
+    }
+
+    fn link(first: &str, second: &str, traits: &[(&str, &str)]) -> LinkConfig {
+        LinkConfig {
+            layers: [first.to_string(), second.to_string()],
+            traits: (!traits.is_empty()).then(|| {
+                traits
+                    .iter()
+                    .map(|(from, to)| (from.to_string(), to.to_string()))
+                    .collect()
+            }),
+        }
+    }
+
+    #[test]
+    fn links_the_later_layer_to_the_earlier_one() {
+        let mut layers = layers(&[
+            ("back", &[("short", 1), ("long", 1), ("None", 1)]),
+            ("body", &[("a", 1)]),
+            ("front", &[("long", 1), ("bob", 1), ("None", 0)]),
+        ]);
+        let config = AppConfig {
+            layers: layer_cfgs(&["back", "body", "front"]),
+            ..Default::default()
+        };
+
+        // listed later first, the mapping is turned around
+        let link = resolve_link(
+            &config,
+            &layers.trait_sets,
+            &link("front", "back", &[("bob", "short")]),
+        )
+        .unwrap();
+        assert_eq!((link.leader, link.follower), (0, 2));
+        assert_eq!(link.traits, vec![1, 0, 2]);
+
+        layers.links = vec![link];
+        let combinations = layers
+            .enumerate(&config.layers)
+            .unwrap()
             .into_iter()
-            .map(|t| t.as_bytes().to_owned())
-            .collect();
-        sorted.sort();
+            .map(|(combination, _)| combination)
+            .collect::<Vec<Vec<usize>>>();
+        assert_eq!(
+            combinations,
+            vec![vec![0, 0, 1], vec![1, 0, 0], vec![2, 0, 2]]
+        );
+    }
+
+    #[test]
+    fn refuses_rules_on_a_follower() {
+        let layers = layers(&[("back", &[("long", 1)]), ("front", &[("long", 1)])]);
+        let mut config = AppConfig {
+            layers: layer_cfgs(&["back", "front"]),
+            ..Default::default()
+        };
+        let link = link("back", "front", &[]);
+        assert!(resolve_link(&config, &layers.trait_sets, &link).is_ok());
+
+        config.layers[1].exclude_if_traits = Some(vec![]);
+        assert!(resolve_link(&config, &layers.trait_sets, &link).is_err());
+
+        // rules on the leader are fine
+        config.layers[1].exclude_if_traits = None;
+        config.layers[0].quota = Some(true);
+        assert!(resolve_link(&config, &layers.trait_sets, &link).is_ok());
+
+        config.layers[1].quota = Some(true);
+        assert!(resolve_link(&config, &layers.trait_sets, &link).is_err());
+    }
 
-        let mut hasher = Keccak256::new();
-        hasher.update(sorted.into_iter().flatten().collect::<Vec<u8>>());
+    fn require(layer: &str, traits: &[&str], require: &[&str]) -> RequireTrait {
+        RequireTrait {
+            when: IfTrait {
+                layer: layer.to_string(),
+                traits: traits.iter().map(|name| name.to_string()).collect(),
+            },
+            require: require.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn pins_a_layer_by_the_first_matching_requirement() {
+        let layers = layers(&[
+            ("bg", &[("red", 1), ("blue", 1)]),
+            ("hat", &[("crown", 1), ("cap", 1), ("None", 1)]),
+        ]);
+        let mut cfgs = layer_cfgs(&["bg", "hat"]);
+        cfgs[1].require_if_traits = Some(vec![
+            require("bg", &["red"], &["crown"]),
+            require("bg", &[], &["cap", "None"]),
+        ]);
+
+        assert_eq!(layers.required(&cfgs, 1, &[0, 2]), Some(vec![0]));
+        assert_eq!(layers.required(&cfgs, 1, &[1, 0]), Some(vec![1, 2]));
+        assert_eq!(layers.required(&cfgs, 0, &[0, 0]), None);
+
+        assert_eq!(layers.unmet_requirement(&cfgs, &[0, 0]), None);
+        assert_eq!(layers.unmet_requirement(&cfgs, &[0, 1]), Some((1, vec![0])));
+        assert_eq!(
+            layers.unmet_requirement(&cfgs, &[1, 0]),
+            Some((1, vec![1, 2]))
+        );
+    }
+
+    #[test]
+    fn leaves_out_requirements_broken_by_excludes() {
+        let layers = layers(&[
+            ("bg", &[("red", 1), ("blue", 1)]),
+            ("hat", &[("crown", 1), ("cap", 1), ("None", 1)]),
+        ]);
+        let mut cfgs = layer_cfgs(&["bg", "hat"]);
+        cfgs[1].require_if_traits = Some(vec![require("bg", &["red"], &["crown"])]);
+
+        let combinations = |cfgs: &[LayerConfig]| {
+            layers
+                .enumerate(cfgs)
+                .unwrap()
+                .into_iter()
+                .map(|(combination, _)| combination)
+                .collect::<Vec<Vec<usize>>>()
+        };
+        assert_eq!(
+            combinations(&cfgs),
+            vec![vec![0, 0], vec![1, 0], vec![1, 1], vec![1, 2]]
+        );
+
+        // red pins the crown, which the exclude then takes off
+        cfgs[1].exclude_if_traits = Some(vec![IfTrait {
+            layer: String::from("bg"),
+            traits: vec![String::from("red")],
+        }]);
+        assert_eq!(
+            combinations(&cfgs),
+            vec![vec![1, 0], vec![1, 1], vec![1, 2]]
+        );
+    }
+
+    #[test]
+    fn splits_tier_weights_evenly() {
+        let mut layers = layers(&[(
+            "hat",
+            &[
+                ("cap", 0),
+                ("beanie", 0),
+                ("fedora", 0),
+                ("crown", 0),
+                ("tiara", 0),
+                ("None", 5),
+            ],
+        )]);
+        let trait_set = &mut layers.trait_sets[0];
+        for (elem, tier) in trait_set
+            .iter_mut()
+            .zip(["common", "common", "common", "rare", "rare"])
+        {
+            elem.tier = Some(tier.to_string());
+        }
+        let tiers = HashMap::from([(String::from("common"), 60), (String::from("rare"), 10)]);
+
+        apply_tiers(trait_set, &tiers).unwrap();
+
+        // scaled by 6 so both tiers split exactly, "None" keeps its share
+        assert_eq!(
+            trait_set
+                .iter()
+                .map(|elem| elem.weight)
+                .collect::<Vec<u32>>(),
+            vec![120, 120, 120, 30, 30, 30]
+        );
+    }
+
+    #[test]
+    fn refuses_tier_weights_too_large_once_split() {
+        let mut layers = layers(&[("hat", &[("cap", 0), ("crown", 0), ("tiara", 0)])]);
+        let trait_set = &mut layers.trait_sets[0];
+        for (elem, tier) in trait_set.iter_mut().zip(["common", "rare", "rare"]) {
+            elem.tier = Some(tier.to_string());
+        }
+        let tiers = HashMap::from([
+            (String::from("common"), u32::MAX),
+            (String::from("rare"), 1),
+        ]);
 
-        format!("{:x}", hasher.finalize())
+        assert!(apply_tiers(trait_set, &tiers).is_err());
     }
 }
@@ -1,6 +1,17 @@
+pub mod cache;
 pub mod cli;
 pub mod config;
+pub mod dna;
+pub mod draw;
 pub mod layers;
+pub mod manifest;
 pub mod metadata;
+pub mod plan;
+pub mod provenance;
+pub mod quotas;
 pub mod rarity;
+pub mod registry;
+pub mod rejections;
+pub mod render;
+pub mod sidecar;
 pub mod utils;
@@ -1,22 +1,29 @@
-use std::{
-    collections::HashSet,
-    fs,
-    path::Path,
-    sync::{Arc, Mutex},
-};
+use std::{collections::HashSet, fs, path::Path};
 
-use anyhow::anyhow;
-use image::RgbaImage;
+use anyhow::{anyhow, Context};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use rand::Rng;
 use rayon::prelude::*;
-use serde_json::{Map, Value};
+use serde_json::Value;
 
-use oink::{cli::Commands, config::AppConfig, layers::Layers, utils};
+use oink::{
+    cache::ImageCache,
+    cli::{Commands, DnaCommands},
+    config::{AppConfig, Sampling},
+    dna::Dna,
+    draw::Draw,
+    layers::{Layers, MAX_ENUMERATION},
+    manifest::{image_path, json_path, sized_image_path, token_images, Manifest, Token},
+    metadata,
+    plan::{Plan, PREFLIGHT_ENUMERATION},
+    provenance::Provenance,
+    registry::DnaRegistry,
+    render, utils,
+};
 
 const OUTPUT: &str = "output";
 
-type CombDef = (Vec<usize>, String);
-type Set = (String, Layers, HashSet<CombDef>);
+type Set<'a> = (&'a AppConfig, Layers, Vec<Token>);
 
 fn main() -> anyhow::Result<()> {
     let cmds = Commands::new();
@@ -25,144 +32,475 @@ fn main() -> anyhow::Result<()> {
 
     match cmds {
         Commands::Clean => utils::clean(output)?,
+        Commands::Plan(args) => {
+            let configs = AppConfig::load_configs(
+                &args.config_folder,
+                Some(&args.bl_file),
+                args.bl_case_sen,
+            )?;
+
+            let plans = configs
+                .par_iter()
+                .map(|config| {
+                    let mut layers = Layers::default();
+                    layers
+                        .load(config)
+                        .with_context(|| format!("unable to load {}", config.config_name))?;
+
+                    Plan::new(config, &layers, args.bl_case_sen, MAX_ENUMERATION)
+                })
+                .collect::<anyhow::Result<Vec<Plan>>>()?;
+
+            for plan in &plans {
+                println!("{}", plan);
+            }
+            check_plans(&plans)?;
+        }
+        Commands::Dna(DnaCommands::Encode(args)) => {
+            let configs = AppConfig::load_configs(&args.config_folder, None, false)?;
+            let config = configs
+                .iter()
+                .find(|config| config.config_name == args.config)
+                .with_context(|| format!("no config named {}", args.config))?;
+
+            let traits = args
+                .traits
+                .iter()
+                .map(|t| {
+                    t.split_once('=')
+                        .map(|(layer, name)| (layer.to_string(), name.to_string()))
+                        .with_context(|| format!("{} is not formatted as layer=trait", t))
+                })
+                .collect::<anyhow::Result<Vec<(String, String)>>>()?;
+
+            let mut layers = Layers::default();
+            layers.load(config)?;
+            let def = layers.lookup(&config.layers, &traits)?;
+            let (_, dna) = layers.describe(&config.layers, &def);
+
+            println!("{}", dna.encode());
+            println!("{}", dna);
+        }
+        Commands::Dna(DnaCommands::Decode(args)) => {
+            let dna = if args.dna.starts_with('v') {
+                Dna::decode(&args.dna)?
+            } else {
+                let manifest = Manifest::load(output)?;
+                let token_path = Path::new(&args.dna);
+                // a generated file is <config>/<folder>/<number>.<ext>
+                let token = match (
+                    token_path
+                        .file_stem()
+                        .and_then(|stem| stem.to_str()?.parse().ok()),
+                    token_path
+                        .parent()
+                        .and_then(Path::parent)
+                        .and_then(Path::file_name)
+                        .and_then(|name| name.to_str()),
+                ) {
+                    (Some(number), Some(config_name)) => manifest.find_number(config_name, number),
+                    _ => manifest.find_dna(&args.dna).map(|(_, token)| token),
+                };
+
+                let token = token.with_context(|| format!("no token found for {}", args.dna))?;
+                println!("token #{}", token.number);
+                Dna::decode(&token.code)?
+            };
+
+            println!("{}", dna);
+        }
+        Commands::Reveal(args) => {
+            let mut manifest = Manifest::load(output)?;
+            let mut provenance = Provenance::load(output)?;
+            let configs = AppConfig::load_configs(&args.config_folder, None, false)?;
+
+            let loaded = configs
+                .par_iter()
+                .filter(|config| !manifest.tokens(&config.config_name).is_empty())
+                .map(|config| {
+                    let mut layers = Layers::default();
+                    layers
+                        .load(config)
+                        .with_context(|| format!("unable to load {}", config.config_name))?;
+                    manifest.verify(&config.config_name, &layers)?;
+
+                    Ok((config, layers))
+                })
+                .collect::<anyhow::Result<Vec<(&AppConfig, Layers)>>>()?;
+
+            let renumbers = provenance.reveal(&mut manifest, args.starting_index)?;
+
+            // every token needs its config before any file is touched
+            let renumbers = renumbers
+                .iter()
+                .map(|renumber| {
+                    configs
+                        .iter()
+                        .find(|config| config.config_name == renumber.config_name)
+                        .map(|config| (renumber, config))
+                        .with_context(|| format!("no config found for {}", renumber.config_name))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
+            // numbers are swapped around, so images go through a temporary name
+            for (renumber, config) in &renumbers {
+                for from in token_images(output, config, renumber.from) {
+                    fs::rename(&from, from.with_extension("reveal"))?;
+                }
+                fs::remove_file(json_path(output, &renumber.config_name, renumber.from))?;
+            }
+            for (renumber, config) in &renumbers {
+                for (from, to) in token_images(output, config, renumber.from)
+                    .into_iter()
+                    .zip(token_images(output, config, renumber.to))
+                {
+                    fs::rename(from.with_extension("reveal"), to)?;
+                }
+            }
+
+            for (config, layers) in &loaded {
+                for token in manifest.tokens(&config.config_name) {
+                    write_metadata(output, config, layers, token)?;
+                }
+            }
+
+            manifest.save(output)?;
+            provenance.save(output)?;
+            println!(
+                "Revealed {} tokens with starting index {}",
+                renumbers.len(),
+                args.starting_index
+            );
+        }
         Commands::Gen(args) => {
             println!("\n ------- Init Configs ------- \n");
             // Prep folders
-            utils::clean(output)?;
-            fs::create_dir(output)?;
+            let mut manifest = if args.resume || args.extend.is_some() {
+                let manifest = Manifest::load(output)?;
+                if manifest.starting_index.is_some() {
+                    return Err(anyhow!("the collection was revealed, it can't be changed"));
+                }
+                if args.start.is_some_and(|start| start != manifest.start)
+                    || args
+                        .numbering
+                        .is_some_and(|numbering| numbering != manifest.numbering)
+                {
+                    return Err(anyhow!(
+                        "the output is numbered from {} per {:?}, it can't be changed",
+                        manifest.start,
+                        manifest.numbering
+                    ));
+                }
+                manifest
+            } else {
+                utils::clean(output)?;
+                fs::create_dir(output)?;
+                Manifest {
+                    start: args.start.unwrap_or(1),
+                    numbering: args.numbering.unwrap_or_default(),
+                    ..Default::default()
+                }
+            };
 
             let multi_proc_sty = ProgressStyle::with_template(
                 "{msg}\n [{elapsed_precise}] {bar:40.green/blue} {pos:>7}/{len:7} \n",
             )?
             .progress_chars("##-");
             let conf_progress = MultiProgress::new();
-            let configs =
-                AppConfig::load_configs(&args.config_folder, &args.bl_file, args.bl_case_sen)?;
+            let configs = AppConfig::load_configs(
+                &args.config_folder,
+                Some(&args.bl_file),
+                args.bl_case_sen,
+            )?;
 
-            // for keeping track of uniqueness
-            let a_all_dna: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
-            // config specific sets
-            let a_all_sets: Arc<Mutex<Vec<Set>>> = Arc::new(Mutex::new(vec![]));
+            let seed = match (args.seed, args.resume || args.extend.is_some()) {
+                (Some(seed), _) => seed,
+                (None, true) => manifest.seed,
+                (None, false) => rand::thread_rng().gen(),
+            };
+            // the manifest keeps the seed of the tokens it already has
+            if args.extend.is_some() {
+                manifest.extend_seeds.push(seed);
+            } else if !args.resume {
+                manifest.seed = seed;
+            }
+            println!("Using seed {}", seed);
 
-            configs
-                .into_iter()
-                .collect::<Vec<AppConfig>>()
+            // load layers in parallel, par_iter keeps the configs order
+            let loaded = configs
                 .par_iter()
-                .for_each(|config| {
-                    let progress = conf_progress.add(ProgressBar::new(config.amount as u64));
+                .map(|config| {
+                    let amount = args.extend.unwrap_or(config.amount);
+                    let progress = conf_progress.add(ProgressBar::new(amount as u64));
                     progress.set_style(multi_proc_sty.clone());
                     progress.set_message(format!("{} -> Loading", config.config_name));
 
                     let mut layers = Layers::default();
-                    match layers.load(&config) {
+                    match layers.load(config) {
                         Ok(_) => {}
-                        Err(_) => {
-                            panic!("unable to load layers")
+                        Err(e) => {
+                            panic!("unable to load layers: {:#}", e)
                         }
                     }
 
-                    let mut fail_count = 0;
-                    let mut uniques = HashSet::new();
-                    let mut count = 1;
-
-                    match a_all_dna.lock() {
-                        Ok(mut all_dna_l) => {
-                            while count <= config.amount {
-                                let (def, traits, dna) = layers.create_unique(&config.layers);
-
-                                if !config.is_bl(&traits, args.bl_case_sen)
-                                    && all_dna_l.insert(dna.clone())
-                                {
-                                    uniques.insert((def, dna));
-                                    count += 1;
-                                    progress.inc(1);
-                                } else {
-                                    fail_count += 1;
-                                    if fail_count > config.tolerance {
-                                        panic!(
-                                            "You need more features or traits to generate {}",
-                                            config.amount
-                                        );
-                                    }
-                                }
-                            }
-                        }
-                        Err(_) => panic!("unable to accquire lock"),
+                    // create ouput folder
+                    fs::create_dir_all(output.join(&config.config_name).join("image"))
+                        .expect("unable to create config image output folder");
+                    fs::create_dir_all(output.join(&config.config_name).join("json"))
+                        .expect("unable to create config json output folder");
+                    for size in config.sizes.iter().flatten() {
+                        fs::create_dir_all(output.join(&config.config_name).join(&size.name))
+                            .expect("unable to create config size output folder");
                     }
-                    match a_all_sets.lock() {
-                        Ok(mut sets_l) => {
-                            sets_l.push((config.config_name.to_string(), layers, uniques));
+
+                    (progress, layers)
+                })
+                .collect::<Vec<(ProgressBar, Layers)>>();
+
+            for (config, (_, layers)) in configs.iter().zip(&loaded) {
+                manifest.verify(&config.config_name, layers)?;
+                manifest.verify_scope(
+                    &config.config_name,
+                    &config.uniqueness_scope(args.uniqueness.as_ref()),
+                )?;
+            }
+
+            let mut sets: Vec<Set> = vec![];
+
+            if args.resume {
+                for (config, (progress, layers)) in configs.iter().zip(loaded) {
+                    let tokens = manifest.tokens(&config.config_name).to_vec();
+                    if tokens.is_empty() {
+                        return Err(anyhow!(
+                            "{} has no tokens in the manifest, use --extend to generate some",
+                            config.config_name
+                        ));
+                    }
+
+                    progress.finish_with_message(format!("{} -> Loaded", config.config_name));
+                    sets.push((config, layers, tokens));
+                }
+            } else {
+                if args.extend.is_some()
+                    && configs
+                        .iter()
+                        .any(|config| config.layers.iter().any(|l| l.quota.unwrap_or(false)))
+                {
+                    return Err(anyhow!("configs with quota layers can't be extended"));
+                }
+
+                let plans = configs
+                    .par_iter()
+                    .zip(&loaded)
+                    .map(|(config, (_, layers))| {
+                        // exhaustive sampling enumerates everything anyway
+                        let limit = match config.sampling {
+                            Sampling::Random => PREFLIGHT_ENUMERATION,
+                            Sampling::Exhaustive => MAX_ENUMERATION,
+                        };
+                        let mut plan = Plan::new(config, layers, args.bl_case_sen, limit)?;
+                        if let Some(extend) = args.extend {
+                            plan.amount = manifest.tokens(&config.config_name).len() + extend;
                         }
-                        Err(_) => panic!("unable to lock mutex"),
+                        Ok(plan)
+                    })
+                    .collect::<anyhow::Result<Vec<Plan>>>()?;
+                check_plans(&plans)?;
+
+                // for keeping track of uniqueness per scope, what was already generated
+                // holds its DNAs before any round
+                let scope_of = |config_name: &str| match manifest.scope(config_name) {
+                    Some(scope) => scope.to_string(),
+                    None => configs
+                        .iter()
+                        .find(|config| config.config_name == config_name)
+                        .map_or(String::from("global"), |config| {
+                            config.uniqueness_scope(args.uniqueness.as_ref())
+                        }),
+                };
+                let registry = DnaRegistry::new();
+                for config in &manifest.configs {
+                    let scope = scope_of(&config.config_name);
+                    for token in &config.tokens {
+                        registry.claim(&scope, &token.dna, (0, 0));
                     }
+                }
 
-                    // create ouput folder
-                    fs::create_dir(output.join(&config.config_name))
-                        .expect("unable to create config output folder");
-                    fs::create_dir(output.join(&config.config_name).join("image"))
-                      .expect("unable to create config image output folder");
-                    fs::create_dir(output.join(&config.config_name).join("json"))
-                      .expect("unable to create config json output folder");
+                // DNAs of previous drops are excluded whatever the scope, by
+                // trait names as the art may have changed since
+                let mut dropped: HashSet<Vec<(String, String)>> = HashSet::new();
+                for drop in &args.exclude_drop {
+                    let drop_output = if drop.is_file() {
+                        drop.parent().unwrap_or(Path::new("."))
+                    } else {
+                        drop.as_path()
+                    };
+                    let drop_manifest = Manifest::load(drop_output)?;
+                    for token in drop_manifest
+                        .configs
+                        .iter()
+                        .flat_map(|config| &config.tokens)
+                    {
+                        dropped.insert(Dna::decode(&token.code)?.names());
+                    }
+                }
+                if !dropped.is_empty() {
+                    println!("Excluding {} DNAs of previous drops", dropped.len());
+                }
+
+                let mut draws = configs
+                    .iter()
+                    .zip(&loaded)
+                    .map(|(config, (_, layers))| {
+                        let existing = manifest.tokens(&config.config_name);
+                        // an extension must not replay the draws of the previous runs
+                        let rng = if existing.is_empty() {
+                            utils::config_rng(seed, &config.config_name)
+                        } else {
+                            utils::config_rng(
+                                seed,
+                                &format!("{}+{}", config.config_name, existing.len()),
+                            )
+                        };
+
+                        Draw::new(
+                            config,
+                            layers,
+                            args.extend.unwrap_or(config.amount),
+                            config.uniqueness_scope(args.uniqueness.as_ref()),
+                            rng,
+                            existing.iter().map(|token| token.dna.clone()).collect(),
+                        )
+                    })
+                    .collect::<anyhow::Result<Vec<Draw>>>()?;
+
+                // configs draw and claim concurrently, a DNA claimed by several
+                // configs in the same round goes to the first config in name
+                // order, so a seed always gives the same DNAs whatever the
+                // thread count
+                let mut round = 1;
+                while draws.iter().any(|draw| !draw.is_done()) {
+                    draws
+                        .par_iter_mut()
+                        .enumerate()
+                        .filter(|(_, draw)| !draw.is_done())
+                        .for_each(|(index, draw)| {
+                            draw.draw(&registry, &dropped, (round, index), args.bl_case_sen)
+                        });
+
+                    draws.par_iter_mut().zip(&loaded).enumerate().for_each(
+                        |(index, (draw, (progress, _)))| {
+                            progress.inc(draw.settle(&registry, (round, index)) as u64);
+                        },
+                    );
+
+                    round += 1;
+                }
+
+                for draw in &draws {
+                    println!("{}", draw.rejections);
+                    fs::write(
+                        output
+                            .join(&draw.config.config_name)
+                            .join("rejections.json"),
+                        serde_json::to_string_pretty(&draw.rejections)?,
+                    )?;
+                }
+                if let Some(error) = draws.iter().find_map(Draw::error) {
+                    return Err(error);
+                }
+
+                // numbers are handed out in configs order
+                let accepted = draws
+                    .into_iter()
+                    .map(|draw| draw.accepted)
+                    .collect::<Vec<Vec<(Vec<usize>, Dna)>>>();
+                for ((config, (progress, layers)), accepted) in
+                    configs.iter().zip(loaded).zip(accepted)
+                {
+                    let first_number = manifest.next_number(&config.config_name);
+                    let tokens = accepted
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, (def, dna))| {
+                            Token::new(first_number + index, &dna, def, &layers)
+                        })
+                        .collect();
+
+                    manifest.push(
+                        &config.config_name,
+                        config.uniqueness_scope(args.uniqueness.as_ref()),
+                        tokens,
+                    );
+                    let tokens = manifest.tokens(&config.config_name).to_vec();
+                    sets.push((config, layers, tokens));
 
                     progress.finish_with_message(format!("{} -> Loaded", config.config_name));
-                });
+                }
+
+                // saved before rendering so an interrupted run can be resumed
+                manifest.save(output)?;
+            }
             // conf_progress.clear()?;
 
             // Generate the images
             println!("\n ------- Generating ------- \n");
             let gen_progresses = MultiProgress::new();
+            let cache = ImageCache::new(args.cache_mb * 1024 * 1024);
 
-            let sets = Arc::try_unwrap(a_all_sets)
-                .map_err(|_| anyhow!("unable to unwrap arc of sets"))?
-                .into_inner()
-                .map_err(|_| anyhow!("unable to take ownership of mutex"))?;
+            sets.par_iter().for_each(|set_data| {
+                let (config, layers, set) = set_data;
+                let cfg_name = &config.config_name;
 
-            sets.into_iter()
-                .collect::<Vec<Set>>()
-                .par_iter()
-                .for_each(|set_data| {
-                    let (cfg_name, layers, set) = set_data;
+                // only render what is missing, json is written last so it marks
+                // a finished token
+                let missing = set
+                    .iter()
+                    .filter(|token| {
+                        token_images(output, config, token.number)
+                            .iter()
+                            .any(|path| !path.exists())
+                            || !json_path(output, cfg_name, token.number).exists()
+                    })
+                    .collect::<Vec<&Token>>();
 
-                    let progress = gen_progresses.add(ProgressBar::new(set.len() as u64));
-                    progress.set_style(multi_proc_sty.clone());
-                    progress.set_message(format!("{} -> Generating NFTs", cfg_name));
-
-                    set.iter()
-                        .collect::<Vec<&(Vec<usize>, String)>>()
-                        .par_iter()
-                        .for_each(|comb| {
-                            let (def, dna) = comb;
-                            let mut base = RgbaImage::new(layers.width, layers.height);
-                            let mut traits_map = Map::new();
-                            let cfg_image_output = output.join(cfg_name).join("image");
-                            let cfg_json_output = output.join(cfg_name).join("json");
-
-                            for (index, trait_list) in def.iter().zip(&layers.trait_sets) {
-                                let nft_trait = &trait_list[*index];
-                                traits_map.insert(
-                                    nft_trait.layer.to_owned(),
-                                    Value::String(nft_trait.name.to_owned()),
-                                );
-                                if let Some(image) = &nft_trait.image {
-                                    utils::merge(&mut base, image);
-                                }
-                            }
-
-                            let nft_image_path = cfg_image_output.join(format!("{}.png", dna));
-                            base.save(nft_image_path).expect("failed to create image");
-
-                            // Write attrs
-                            let attributes_path = cfg_json_output.join(format!("{}.json", dna));
-                            let attributes = serde_json::to_string_pretty(&traits_map)
-                                .expect("failed to create attributes");
-
-                            fs::write(attributes_path, attributes)
-                                .expect("failed to create attributes");
-
-                            progress.inc(1);
-                        });
-                    progress.finish_with_message(format!("{} -> Generation completed", cfg_name));
+                let progress = gen_progresses.add(ProgressBar::new(missing.len() as u64));
+                progress.set_style(multi_proc_sty.clone());
+                progress.set_message(format!("{} -> Generating NFTs", cfg_name));
+
+                missing.par_iter().for_each(|token| {
+                    let frames = render::render(config, layers, &token.def, &cache)
+                        .expect("failed to load trait image");
+                    let animated = layers.is_animated(&token.def);
+
+                    render::save(
+                        &frames,
+                        &image_path(output, config, token.number),
+                        &config.format,
+                        animated,
+                    )
+                    .expect("failed to create image");
+                    for size in config.sizes.iter().flatten() {
+                        render::save(
+                            &render::resize(&frames, size),
+                            &sized_image_path(output, config, &size.name, token.number),
+                            &config.format,
+                            animated,
+                        )
+                        .expect("failed to create resized image");
+                    }
+
+                    write_metadata(output, config, layers, token)
+                        .expect("failed to create metadata");
+
+                    progress.inc(1);
                 });
+                progress.finish_with_message(format!("{} -> Generation completed", cfg_name));
+            });
+
+            let provenance = Provenance::new(&manifest, &configs, output)?;
+            provenance.save(output)?;
+            println!("Provenance hash {}", provenance.provenance);
 
             // Calculate rarity
             // let mut rarity = Rarity::new(config.amount);
@@ -187,3 +525,53 @@ fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Fails when a config asks for more combinations than its layers can produce
+fn check_plans(plans: &[Plan]) -> anyhow::Result<()> {
+    let infeasible = plans
+        .iter()
+        .filter(|plan| !plan.is_feasible())
+        .map(|plan| plan.to_string())
+        .collect::<Vec<String>>();
+
+    if infeasible.is_empty() {
+        return Ok(());
+    }
+
+    Err(anyhow!(
+        "not enough unique combinations, add more traits or lower the amount\n{}",
+        infeasible.join("\n")
+    ))
+}
+
+/// Writes the metadata json of a token
+fn write_metadata(
+    output: &Path,
+    config: &AppConfig,
+    layers: &Layers,
+    token: &Token,
+) -> anyhow::Result<()> {
+    let mut extra = config.extra.clone();
+    if let Some(details) = metadata::trait_details(layers, &token.def) {
+        extra
+            .get_or_insert_with(Default::default)
+            .insert(String::from("traits"), Value::Object(details));
+    }
+
+    let metadata = metadata::build_with_attributes(
+        metadata::attributes(layers, &config.layers, &token.def),
+        config.policy_id.clone(),
+        config.name.clone(),
+        config.display_name.as_ref(),
+        extra,
+        token.number,
+        config.format.media_type(layers.is_animated(&token.def)),
+    );
+
+    fs::write(
+        json_path(output, &config.config_name, token.number),
+        metadata,
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,273 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{cli::Numbering, config::AppConfig, dna::Dna, layers::Layers};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+pub fn image_path(output: &Path, config: &AppConfig, number: usize) -> PathBuf {
+    output.join(&config.config_name).join("image").join(format!(
+        "{}.{}",
+        number,
+        config.format.extension()
+    ))
+}
+
+/// Image of a token resized to one of the config `sizes`
+pub fn sized_image_path(
+    output: &Path,
+    config: &AppConfig,
+    size_name: &str,
+    number: usize,
+) -> PathBuf {
+    output
+        .join(&config.config_name)
+        .join(size_name)
+        .join(format!("{}.{}", number, config.format.extension()))
+}
+
+/// Every image written for a token, full size first
+pub fn token_images(output: &Path, config: &AppConfig, number: usize) -> Vec<PathBuf> {
+    let mut images = vec![image_path(output, config, number)];
+
+    for size in config.sizes.iter().flatten() {
+        images.push(sized_image_path(output, config, &size.name, number));
+    }
+
+    images
+}
+
+pub fn json_path(output: &Path, config_name: &str, number: usize) -> PathBuf {
+    output
+        .join(config_name)
+        .join("json")
+        .join(format!("{}.json", number))
+}
+
+/// Everything generated into an output folder, so a run can be resumed or extended
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct Manifest {
+    /// Seed of the first run
+    pub seed: u64,
+    /// Seeds of the `--extend` runs, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extend_seeds: Vec<u64>,
+    /// Number of the first token
+    pub start: usize,
+    pub numbering: Numbering,
+    /// Set once the token numbers were shifted by `oink reveal`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub starting_index: Option<usize>,
+    pub configs: Vec<ConfigManifest>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct ConfigManifest {
+    pub config_name: String,
+    /// Uniqueness scope the tokens were drawn in, see `AppConfig::uniqueness_scope`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    pub tokens: Vec<Token>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Token {
+    pub number: usize,
+    /// Hash of the DNA
+    pub dna: String,
+    /// Encoded DNA, see `oink dna decode`
+    pub code: String,
+    /// Trait index per layer
+    pub def: Vec<usize>,
+    /// Trait name per layer, to detect art that changed since generation
+    pub traits: Vec<String>,
+}
+
+impl Token {
+    pub fn new(number: usize, dna: &Dna, def: Vec<usize>, layers: &Layers) -> Self {
+        let traits = def
+            .iter()
+            .zip(&layers.trait_sets)
+            .map(|(index, trait_list)| trait_list[*index].name.clone())
+            .collect();
+
+        Self {
+            number,
+            dna: dna.hash(),
+            code: dna.encode(),
+            def,
+            traits,
+        }
+    }
+}
+
+impl Manifest {
+    pub fn load(output: &Path) -> anyhow::Result<Self> {
+        let path = output.join(MANIFEST_FILE);
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("no manifest found at {}", path.display()))?;
+
+        serde_json::from_str(&contents)
+            .with_context(|| format!("unable to parse manifest {}", path.display()))
+    }
+
+    pub fn save(&self, output: &Path) -> anyhow::Result<()> {
+        let path = output.join(MANIFEST_FILE);
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("unable to write manifest {}", path.display()))
+    }
+
+    pub fn tokens(&self, config_name: &str) -> &[Token] {
+        self.configs
+            .iter()
+            .find(|config| config.config_name == config_name)
+            .map_or(&[], |config| &config.tokens)
+    }
+
+    /// Tokens in number order, grouped by config unless numbering is global
+    pub fn ordered(&self) -> Vec<(&str, &Token)> {
+        let mut ordered = self
+            .configs
+            .iter()
+            .enumerate()
+            .flat_map(|(config_idx, config)| {
+                config
+                    .tokens
+                    .iter()
+                    .map(move |token| (config_idx, config.config_name.as_str(), token))
+            })
+            .collect::<Vec<(usize, &str, &Token)>>();
+
+        match self.numbering {
+            Numbering::Config => {
+                ordered.sort_by_key(|(config_idx, _, token)| (*config_idx, token.number))
+            }
+            Numbering::Global => ordered.sort_by_key(|(_, _, token)| token.number),
+        }
+
+        ordered
+            .into_iter()
+            .map(|(_, config_name, token)| (config_name, token))
+            .collect()
+    }
+
+    /// Token with the given DNA hash
+    pub fn find_dna(&self, dna: &str) -> Option<(&str, &Token)> {
+        self.configs.iter().find_map(|config| {
+            config
+                .tokens
+                .iter()
+                .find(|token| token.dna == dna)
+                .map(|token| (config.config_name.as_str(), token))
+        })
+    }
+
+    /// Token of a config with the given number
+    pub fn find_number(&self, config_name: &str, number: usize) -> Option<&Token> {
+        self.tokens(config_name)
+            .iter()
+            .find(|token| token.number == number)
+    }
+
+    /// Number of the next token of a config
+    pub fn next_number(&self, config_name: &str) -> usize {
+        let last = match self.numbering {
+            Numbering::Config => self.tokens(config_name).iter().map(|t| t.number).max(),
+            Numbering::Global => self
+                .configs
+                .iter()
+                .flat_map(|config| config.tokens.iter().map(|t| t.number))
+                .max(),
+        };
+
+        last.map_or(self.start, |last| last + 1)
+    }
+
+    pub fn push(&mut self, config_name: &str, scope: String, tokens: Vec<Token>) {
+        match self
+            .configs
+            .iter_mut()
+            .find(|config| config.config_name == config_name)
+        {
+            Some(config) => {
+                config.scope = Some(scope);
+                config.tokens.extend(tokens);
+            }
+            None => self.configs.push(ConfigManifest {
+                config_name: config_name.to_string(),
+                scope: Some(scope),
+                tokens,
+            }),
+        }
+    }
+
+    /// Uniqueness scope the tokens of a config were drawn in, if recorded
+    pub fn scope(&self, config_name: &str) -> Option<&str> {
+        self.configs
+            .iter()
+            .find(|config| config.config_name == config_name)
+            .and_then(|config| config.scope.as_deref())
+    }
+
+    /// Fails when a config would now be unique in another scope than its tokens
+    pub fn verify_scope(&self, config_name: &str, scope: &str) -> anyhow::Result<()> {
+        match self.scope(config_name) {
+            Some(recorded) if recorded != scope => Err(anyhow!(
+                "{} was generated with uniqueness {}, it can't be changed to {}",
+                config_name,
+                recorded,
+                scope
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails when the layers no longer match the traits recorded for the tokens
+    pub fn verify(&self, config_name: &str, layers: &Layers) -> anyhow::Result<()> {
+        for token in self.tokens(config_name) {
+            let matches = token.def.len() == layers.trait_sets.len()
+                && token
+                    .def
+                    .iter()
+                    .zip(&token.traits)
+                    .zip(&layers.trait_sets)
+                    .all(|((index, name), trait_list)| {
+                        trait_list
+                            .get(*index)
+                            .is_some_and(|elem| &elem.name == name)
+                    });
+
+            if !matches {
+                return Err(anyhow!(
+                    "{} token #{} no longer matches the layers, its traits were {:?}",
+                    config_name,
+                    token.number,
+                    token.traits
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_uniqueness_scope_of_a_config() {
+        let mut manifest = Manifest::default();
+        assert!(manifest.verify_scope("bears", "global").is_ok());
+
+        manifest.push("bears", String::from("config:bears"), vec![]);
+        assert!(manifest.verify_scope("bears", "config:bears").is_ok());
+        assert!(manifest.verify_scope("bears", "global").is_err());
+        assert!(manifest.verify_scope("cats", "global").is_ok());
+    }
+}
@@ -1,7 +1,10 @@
 use serde::Serialize;
 use serde_json::{json, Map, Value};
 
-use crate::config::AppConfig;
+use crate::{
+    config::{AppConfig, LayerConfig},
+    layers::Layers,
+};
 
 #[derive(Serialize)]
 pub struct Attribute {
@@ -12,7 +15,13 @@ pub struct Attribute {
 pub fn build_template(config: &AppConfig) -> String {
     let mut attributes = Map::new();
 
+    // linked layers are listed once, under the earlier one
+    let followers = config.link_followers();
+
     for (index, attr) in config.layers.iter().enumerate() {
+        if followers.contains(&index) {
+            continue;
+        }
         let template = Value::String(format!("<attribute{}>", index));
 
         attributes.insert(
@@ -67,6 +76,76 @@ pub fn build_template(config: &AppConfig) -> String {
     serde_json::to_string_pretty(&json).expect("this should not fail")
 }
 
+/// Trait name per layer of a combination, with the extra attributes of the
+/// trait sidecars and the trait tiers
+pub fn attributes(
+    layers: &Layers,
+    layer_configs: &[LayerConfig],
+    def: &[usize],
+) -> Map<String, Value> {
+    let mut attributes = Map::new();
+
+    for (layer_idx, ((index, trait_list), layer_config)) in def
+        .iter()
+        .zip(&layers.trait_sets)
+        .zip(layer_configs)
+        .enumerate()
+    {
+        // linked layers are listed once, under their leader
+        if layers.link_of(layer_idx).is_some() {
+            continue;
+        }
+        let nft_trait = &trait_list[*index];
+        let sidecar = nft_trait.sidecar.as_ref();
+
+        attributes.insert(
+            nft_trait.layer.to_owned(),
+            Value::String(
+                sidecar
+                    .and_then(|sidecar| sidecar.display_name.clone())
+                    .unwrap_or_else(|| nft_trait.name.to_owned()),
+            ),
+        );
+        if let Some(extra) = sidecar.and_then(|sidecar| sidecar.attributes.as_ref()) {
+            attributes.extend(extra.clone());
+        }
+        if let (Some(name), Some(tier)) = (&layer_config.tier_attribute, &nft_trait.tier) {
+            attributes.insert(name.to_owned(), Value::String(tier.to_owned()));
+        }
+    }
+
+    attributes
+}
+
+/// Description and tags per layer of the traits of a combination that have
+/// them
+pub fn trait_details(layers: &Layers, def: &[usize]) -> Option<Map<String, Value>> {
+    let mut details = Map::new();
+
+    for (layer_idx, (index, trait_list)) in def.iter().zip(&layers.trait_sets).enumerate() {
+        if layers.link_of(layer_idx).is_some() {
+            continue;
+        }
+        let nft_trait = &trait_list[*index];
+        let Some(sidecar) = &nft_trait.sidecar else {
+            continue;
+        };
+
+        let mut detail = Map::new();
+        if let Some(description) = &sidecar.description {
+            detail.insert(String::from("description"), json!(description));
+        }
+        if let Some(tags) = &sidecar.tags {
+            detail.insert(String::from("tags"), json!(tags));
+        }
+        if !detail.is_empty() {
+            details.insert(nft_trait.layer.to_owned(), Value::Object(detail));
+        }
+    }
+
+    (!details.is_empty()).then_some(details)
+}
+
 pub fn build_with_attributes(
     attributes: Map<String, Value>,
     policy_id: Option<String>,
@@ -74,6 +153,7 @@ pub fn build_with_attributes(
     display_name: Option<&String>,
     extra: Option<Map<String, Value>>,
     count: usize,
+    media_type: &str,
 ) -> String {
     let mut asset_name = Map::new();
 
@@ -92,7 +172,7 @@ pub fn build_with_attributes(
 
     asset_name.insert(
         String::from("mediaType"),
-        Value::String(String::from("image/png")),
+        Value::String(String::from(media_type)),
     );
 
     asset_name.insert(
@@ -103,7 +183,7 @@ pub fn build_with_attributes(
                 || format!("{} #{}", name, count),
                 |display_name| format!("{} #{}", display_name, count),
             ),
-            "mediaType": "image/png",
+            "mediaType": media_type,
             "src": "<ipfs_link>"
           }
         ]),
@@ -0,0 +1,210 @@
+use std::fmt;
+
+use crate::{config::AppConfig, layers::Layers};
+
+/// Raw combinations `gen` enumerates to check a randomly sampled config, above
+/// it the raw count is used as the bound
+pub const PREFLIGHT_ENUMERATION: u128 = 100_000;
+
+/// How many unique combinations a config can produce
+pub struct Plan {
+    pub config_name: String,
+    pub amount: usize,
+    /// Combinations that can be drawn, before excludes and blacklist
+    pub raw: u128,
+    /// Distinct combinations left after excludes and blacklist, `None` when
+    /// there are too many raw combinations to enumerate
+    pub possible: Option<usize>,
+    /// Raw combinations that render the same as another one after excludes,
+    /// or that break a requirement
+    pub collapsed: u128,
+    pub blacklisted: usize,
+    /// Quota traits with a count above the possible combinations that have
+    /// them, as `layer/trait` lines
+    pub short_quotas: Vec<String>,
+}
+
+impl Plan {
+    /// Counts the combinations of a config, enumerating them when there are no
+    /// more than `limit` and excludes, requirements or the blacklist can
+    /// remove some
+    pub fn new(
+        config: &AppConfig,
+        layers: &Layers,
+        bl_case_sen: bool,
+        limit: u128,
+    ) -> anyhow::Result<Self> {
+        let raw = layers.raw_combinations();
+        let mut plan = Plan {
+            config_name: config.config_name.clone(),
+            amount: config.amount,
+            raw,
+            possible: None,
+            collapsed: 0,
+            blacklisted: 0,
+            short_quotas: vec![],
+        };
+
+        // without rules every raw combination is distinct and allowed
+        let has_blacklist = config.bl.as_ref().is_some_and(|bl| !bl.is_empty());
+        let has_quotas = config
+            .layers
+            .iter()
+            .any(|layer_config| layer_config.quota.unwrap_or(false));
+        if !layers.has_rules(&config.layers) && !has_blacklist && !has_quotas {
+            plan.possible = usize::try_from(raw).ok();
+            return Ok(plan);
+        }
+
+        if raw > limit {
+            return Ok(plan);
+        }
+
+        let combinations = layers.enumerate(&config.layers)?;
+        plan.collapsed = raw - combinations.len() as u128;
+        let allowed = combinations
+            .iter()
+            .map(|(combination, _)| combination)
+            .filter(|combination| {
+                let (traits, _) = layers.describe(&config.layers, combination);
+                !config.is_bl(&traits, bl_case_sen)
+            })
+            .collect::<Vec<&Vec<usize>>>();
+        plan.blacklisted = combinations.len() - allowed.len();
+        plan.possible = Some(allowed.len());
+
+        // a trait can't be on more tokens than there are combinations with it
+        for (layer_idx, (trait_list, layer_config)) in
+            layers.trait_sets.iter().zip(&config.layers).enumerate()
+        {
+            if !layer_config.quota.unwrap_or(false) {
+                continue;
+            }
+            for (index, elem) in trait_list.iter().enumerate() {
+                let available = allowed
+                    .iter()
+                    .filter(|combination| combination[layer_idx] == index)
+                    .count();
+                if elem.weight as usize > available {
+                    plan.short_quotas.push(format!(
+                        "{}/{}: quota of {} but {} possible combinations have it",
+                        elem.layer, elem.name, elem.weight, available
+                    ));
+                }
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Upper bound of unique combinations
+    pub fn limit(&self) -> u128 {
+        self.possible.map_or(self.raw, |possible| possible as u128)
+    }
+
+    pub fn is_feasible(&self) -> bool {
+        self.amount as u128 <= self.limit() && self.short_quotas.is_empty()
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.possible {
+            Some(possible) => write!(
+                f,
+                "{}: {} requested, {} possible ({} raw, {} collapsed by excludes and requirements, {} blacklisted)",
+                self.config_name,
+                self.amount,
+                possible,
+                self.raw,
+                self.collapsed,
+                self.blacklisted
+            ),
+            None => write!(
+                f,
+                "{}: {} requested, at most {} possible (too many to enumerate excludes and blacklist)",
+                self.config_name, self.amount, self.raw
+            ),
+        }?;
+        for short_quota in &self.short_quotas {
+            write!(f, "\n  {}", short_quota)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::IfTrait,
+        layers::tests::{layer_cfgs, layers},
+    };
+
+    fn config(layers: Vec<crate::config::LayerConfig>) -> AppConfig {
+        AppConfig {
+            config_name: String::from("bears"),
+            amount: 3,
+            layers,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn counts_raw_combinations_without_rules() {
+        let layers = layers(&[
+            ("bg", &[("red", 1), ("blue", 1)]),
+            ("eyes", &[("laser", 1), ("plain", 1), ("None", 1)]),
+        ]);
+        let config = config(layer_cfgs(&["bg", "eyes"]));
+
+        // nothing to enumerate whatever the limit
+        let plan = Plan::new(&config, &layers, false, 0).unwrap();
+
+        assert_eq!(plan.raw, 6);
+        assert_eq!(plan.possible, Some(6));
+    }
+
+    #[test]
+    fn counts_combinations_collapsed_by_excludes() {
+        let layers = layers(&[
+            ("bg", &[("red", 1), ("blue", 1)]),
+            ("eyes", &[("laser", 1), ("plain", 1), ("None", 0)]),
+        ]);
+        let mut cfgs = layer_cfgs(&["bg", "eyes"]);
+        cfgs[1].exclude_if_traits = Some(vec![IfTrait {
+            layer: String::from("bg"),
+            traits: vec![String::from("red")],
+        }]);
+        let config = config(cfgs);
+
+        let plan = Plan::new(&config, &layers, false, 100).unwrap();
+        assert_eq!((plan.raw, plan.possible, plan.collapsed), (4, Some(3), 1));
+        assert!(plan.is_feasible());
+
+        // past the limit only the raw bound is known
+        let plan = Plan::new(&config, &layers, false, 3).unwrap();
+        assert_eq!((plan.possible, plan.limit()), (None, 4));
+    }
+
+    #[test]
+    fn flags_quotas_above_the_combinations_holding_them() {
+        let layers = layers(&[
+            ("bg", &[("red", 1), ("blue", 1)]),
+            ("eyes", &[("laser", 1), ("plain", 3)]),
+        ]);
+        let mut cfgs = layer_cfgs(&["bg", "eyes"]);
+        cfgs[1].quota = Some(true);
+        let mut config = config(cfgs);
+        config.amount = 4;
+
+        // only two backgrounds to wear plain eyes with
+        let plan = Plan::new(&config, &layers, false, 100).unwrap();
+        assert_eq!(plan.possible, Some(4));
+        assert_eq!(
+            plan.short_quotas,
+            vec!["eyes/plain: quota of 3 but 2 possible combinations have it"]
+        );
+        assert!(!plan.is_feasible());
+    }
+}
@@ -0,0 +1,240 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+
+use crate::{
+    cli::Numbering,
+    config::AppConfig,
+    manifest::{image_path, Manifest},
+};
+
+pub const PROVENANCE_FILE: &str = "provenance.json";
+
+/// Hashes of the final images, committed to before the reveal
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Provenance {
+    /// Keccak256 of the image hashes concatenated in token order
+    pub provenance: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub starting_index: Option<usize>,
+    pub tokens: Vec<ProvenanceToken>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ProvenanceToken {
+    pub config_name: String,
+    pub number: usize,
+    pub dna: String,
+    /// Keccak256 of the image file
+    pub hash: String,
+}
+
+/// A token number change made by the reveal
+pub struct Renumber {
+    pub config_name: String,
+    pub from: usize,
+    pub to: usize,
+}
+
+impl Provenance {
+    pub fn new(manifest: &Manifest, configs: &[AppConfig], output: &Path) -> anyhow::Result<Self> {
+        let mut tokens = vec![];
+        let mut concatenated = String::new();
+
+        for (config_name, token) in manifest.ordered() {
+            let config = configs
+                .iter()
+                .find(|config| config.config_name == config_name)
+                .with_context(|| format!("no config found for {}", config_name))?;
+            let image_path = image_path(output, config, token.number);
+            let image = fs::read(&image_path)
+                .with_context(|| format!("unable to read {}", image_path.display()))?;
+            let hash = format!("{:x}", Keccak256::digest(image));
+
+            concatenated.push_str(&hash);
+            tokens.push(ProvenanceToken {
+                config_name: config_name.to_string(),
+                number: token.number,
+                dna: token.dna.clone(),
+                hash,
+            });
+        }
+
+        Ok(Self {
+            provenance: format!("{:x}", Keccak256::digest(concatenated)),
+            starting_index: None,
+            tokens,
+        })
+    }
+
+    pub fn load(output: &Path) -> anyhow::Result<Self> {
+        let path = output.join(PROVENANCE_FILE);
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("no provenance found at {}", path.display()))?;
+
+        serde_json::from_str(&contents)
+            .with_context(|| format!("unable to parse provenance {}", path.display()))
+    }
+
+    pub fn save(&self, output: &Path) -> anyhow::Result<()> {
+        let path = output.join(PROVENANCE_FILE);
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("unable to write provenance {}", path.display()))
+    }
+
+    /// Shifts the token numbers of the manifest by the starting index, the
+    /// token that was `starting_index` places after the first one becomes the
+    /// first one, and so on, wrapping around
+    pub fn reveal(
+        &mut self,
+        manifest: &mut Manifest,
+        starting_index: usize,
+    ) -> anyhow::Result<Vec<Renumber>> {
+        if manifest.starting_index.is_some() || self.starting_index.is_some() {
+            return Err(anyhow!("the collection was already revealed"));
+        }
+
+        // numbers are shifted within each group of consecutive numbers
+        let mut groups: Vec<Vec<(String, usize)>> = vec![];
+        for (config_name, token) in manifest.ordered() {
+            match groups.last_mut() {
+                Some(group)
+                    if manifest.numbering == Numbering::Global || group[0].0 == config_name =>
+                {
+                    group.push((config_name.to_string(), token.number))
+                }
+                _ => groups.push(vec![(config_name.to_string(), token.number)]),
+            }
+        }
+
+        let mut renumbers = vec![];
+        for group in groups {
+            let total = group.len();
+            let offset = starting_index % total;
+
+            for (position, (config_name, from)) in group.iter().enumerate() {
+                let (_, to) = group[(position + total - offset) % total];
+                renumbers.push(Renumber {
+                    config_name: config_name.clone(),
+                    from: *from,
+                    to,
+                });
+            }
+        }
+
+        // every number moves at once, looked up by the old numbers
+        let moves = renumbers
+            .iter()
+            .map(|r| ((r.config_name.as_str(), r.from), r.to))
+            .collect::<HashMap<(&str, usize), usize>>();
+
+        for config in manifest.configs.iter_mut() {
+            for token in config.tokens.iter_mut() {
+                if let Some(to) = moves.get(&(config.config_name.as_str(), token.number)) {
+                    token.number = *to;
+                }
+            }
+        }
+        for token in self.tokens.iter_mut() {
+            if let Some(to) = moves.get(&(token.config_name.as_str(), token.number)) {
+                token.number = *to;
+            }
+        }
+
+        manifest.starting_index = Some(starting_index);
+        self.starting_index = Some(starting_index);
+
+        Ok(renumbers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{ConfigManifest, Token};
+
+    fn manifest(numbering: Numbering, configs: &[(&str, &[usize])]) -> Manifest {
+        Manifest {
+            start: 1,
+            numbering,
+            configs: configs
+                .iter()
+                .map(|(config_name, numbers)| ConfigManifest {
+                    config_name: config_name.to_string(),
+                    scope: None,
+                    tokens: numbers
+                        .iter()
+                        .map(|number| Token {
+                            number: *number,
+                            dna: format!("{}{}", config_name, number),
+                            code: String::new(),
+                            def: vec![],
+                            traits: vec![],
+                        })
+                        .collect(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn provenance() -> Provenance {
+        Provenance {
+            provenance: String::new(),
+            starting_index: None,
+            tokens: vec![],
+        }
+    }
+
+    fn moves(renumbers: &[Renumber]) -> Vec<(&str, usize, usize)> {
+        renumbers
+            .iter()
+            .map(|r| (r.config_name.as_str(), r.from, r.to))
+            .collect()
+    }
+
+    #[test]
+    fn shifts_numbers_per_config() {
+        let mut manifest = manifest(Numbering::Config, &[("a", &[1, 2, 3, 4]), ("b", &[1, 2])]);
+
+        let renumbers = provenance().reveal(&mut manifest, 5).unwrap();
+
+        // a is shifted by 5 % 4, b by 5 % 2
+        assert_eq!(
+            moves(&renumbers),
+            vec![
+                ("a", 1, 4),
+                ("a", 2, 1),
+                ("a", 3, 2),
+                ("a", 4, 3),
+                ("b", 1, 2),
+                ("b", 2, 1)
+            ]
+        );
+        assert_eq!(manifest.configs[0].tokens[1].number, 1);
+        assert_eq!(manifest.starting_index, Some(5));
+    }
+
+    #[test]
+    fn shifts_global_numbers_across_configs() {
+        let mut manifest = manifest(Numbering::Global, &[("a", &[1, 2]), ("b", &[3])]);
+
+        let renumbers = provenance().reveal(&mut manifest, 1).unwrap();
+
+        assert_eq!(
+            moves(&renumbers),
+            vec![("a", 1, 3), ("a", 2, 1), ("b", 3, 2)]
+        );
+    }
+
+    #[test]
+    fn reveals_once() {
+        let mut manifest = manifest(Numbering::Config, &[("a", &[1, 2])]);
+        let mut provenance = provenance();
+
+        provenance.reveal(&mut manifest, 1).unwrap();
+        assert!(provenance.reveal(&mut manifest, 1).is_err());
+    }
+}
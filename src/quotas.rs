@@ -0,0 +1,116 @@
+use crate::{config::LayerConfig, layers::Layers};
+
+/// Remaining trait counts of the layers in quota mode
+pub struct Quotas {
+    remaining: Vec<Option<Vec<u32>>>,
+}
+
+impl Quotas {
+    pub fn new(layers: &Layers, layer_cfgs: &[LayerConfig]) -> Self {
+        let remaining = layers
+            .trait_sets
+            .iter()
+            .zip(layer_cfgs)
+            .map(|(trait_list, layer_config)| {
+                layer_config
+                    .quota
+                    .unwrap_or(false)
+                    .then(|| trait_list.iter().map(|elem| elem.weight).collect())
+            })
+            .collect();
+
+        Self { remaining }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining.iter().all(Option::is_none)
+    }
+
+    /// Draw weight of a trait, its remaining count when the layer is in quota mode
+    pub fn weight(&self, layer_idx: usize, trait_idx: usize, weight: u32) -> u32 {
+        match self.remaining.get(layer_idx) {
+            Some(Some(counts)) => counts[trait_idx],
+            _ => weight,
+        }
+    }
+
+    /// Whether every trait of the combination still has some quota left
+    pub fn allows(&self, combination: &[usize]) -> bool {
+        combination
+            .iter()
+            .zip(&self.remaining)
+            .all(|(index, counts)| counts.as_ref().is_none_or(|counts| counts[*index] > 0))
+    }
+
+    pub fn take(&mut self, combination: &[usize]) {
+        for (index, counts) in combination.iter().zip(&mut self.remaining) {
+            if let Some(counts) = counts {
+                counts[*index] -= 1;
+            }
+        }
+    }
+
+    /// Gives back what `take` took for a combination that was not kept
+    pub fn give(&mut self, combination: &[usize]) {
+        for (index, counts) in combination.iter().zip(&mut self.remaining) {
+            if let Some(counts) = counts {
+                counts[*index] += 1;
+            }
+        }
+    }
+
+    /// Traits whose quota was not used up, as `layer/trait: count` lines
+    pub fn unmet(&self, layers: &Layers) -> Vec<String> {
+        self.remaining
+            .iter()
+            .zip(&layers.trait_sets)
+            .filter_map(|(counts, trait_list)| counts.as_ref().map(|counts| (counts, trait_list)))
+            .flat_map(|(counts, trait_list)| counts.iter().zip(trait_list))
+            .filter(|(count, _)| **count > 0)
+            .map(|(count, elem)| format!("{}/{}: {} left", elem.layer, elem.name, count))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layers::tests::{layer_cfgs, layers};
+
+    #[test]
+    fn counts_down_quota_layers_only() {
+        let layers = layers(&[
+            ("bg", &[("red", 5), ("blue", 5)]),
+            ("eyes", &[("laser", 1), ("plain", 2)]),
+        ]);
+        let mut cfgs = layer_cfgs(&["bg", "eyes"]);
+        cfgs[1].quota = Some(true);
+        let mut quotas = Quotas::new(&layers, &cfgs);
+
+        assert!(!quotas.is_empty());
+        assert_eq!(quotas.weight(0, 0, 5), 5);
+        assert_eq!(quotas.weight(1, 1, 2), 2);
+
+        quotas.take(&[0, 0]);
+        assert_eq!(quotas.weight(1, 0, 1), 0);
+        assert!(!quotas.allows(&[1, 0]));
+        assert!(quotas.allows(&[1, 1]));
+        assert_eq!(quotas.unmet(&layers), vec!["eyes/plain: 2 left"]);
+
+        quotas.give(&[0, 0]);
+        assert!(quotas.allows(&[1, 0]));
+        assert_eq!(
+            quotas.unmet(&layers),
+            vec!["eyes/laser: 1 left", "eyes/plain: 2 left"]
+        );
+    }
+
+    #[test]
+    fn is_empty_without_quota_layers() {
+        let layers = layers(&[("bg", &[("red", 1)])]);
+        let quotas = Quotas::new(&layers, &layer_cfgs(&["bg"]));
+
+        assert!(quotas.is_empty());
+        assert!(quotas.allows(&[0]));
+    }
+}
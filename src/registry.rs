@@ -0,0 +1,105 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+};
+
+const SHARDS: usize = 64;
+
+/// Claim priority as `(round, config index)`, lower wins
+pub type Priority = (usize, usize);
+
+/// DNAs claimed per uniqueness scope, sharded so configs can claim concurrently
+///
+/// A DNA claimed twice goes to the lowest priority whatever the order of the
+/// claims, which keeps seeded runs deterministic across thread counts.
+pub struct DnaRegistry {
+    shards: Vec<Mutex<HashMap<(String, String), Priority>>>,
+}
+
+impl Default for DnaRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DnaRegistry {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    fn shard(&self, scope: &str, dna: &str) -> &Mutex<HashMap<(String, String), Priority>> {
+        let mut hasher = DefaultHasher::new();
+        scope.hash(&mut hasher);
+        dna.hash(&mut hasher);
+
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    /// Claims a DNA, false when an earlier round already holds it
+    ///
+    /// Claims of the same round all return true, whether they hold is only
+    /// known once the round is over, see `owns`.
+    pub fn claim(&self, scope: &str, dna: &str, priority: Priority) -> bool {
+        let mut shard = self
+            .shard(scope, dna)
+            .lock()
+            .expect("unable to lock dna shard");
+
+        let owner = shard
+            .entry((scope.to_string(), dna.to_string()))
+            .or_insert(priority);
+        if priority < *owner {
+            *owner = priority;
+        }
+
+        owner.0 == priority.0
+    }
+
+    /// Whether the claim of a DNA still holds once every claim of a round is in
+    pub fn owns(&self, scope: &str, dna: &str, priority: Priority) -> bool {
+        self.shard(scope, dna)
+            .lock()
+            .expect("unable to lock dna shard")
+            .get(&(scope.to_string(), dna.to_string()))
+            .is_some_and(|owner| *owner == priority)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lowest_priority_owns_a_dna_whatever_the_claim_order() {
+        let registry = DnaRegistry::new();
+
+        // same round, both claims hold until the round settles
+        assert!(registry.claim("global", "dna", (1, 2)));
+        assert!(registry.claim("global", "dna", (1, 0)));
+
+        assert!(registry.owns("global", "dna", (1, 0)));
+        assert!(!registry.owns("global", "dna", (1, 2)));
+    }
+
+    #[test]
+    fn earlier_rounds_keep_their_dnas() {
+        let registry = DnaRegistry::new();
+
+        assert!(registry.claim("global", "dna", (0, 3)));
+        assert!(!registry.claim("global", "dna", (1, 0)));
+        assert!(registry.owns("global", "dna", (0, 3)));
+    }
+
+    #[test]
+    fn scopes_are_independent() {
+        let registry = DnaRegistry::new();
+
+        assert!(registry.claim("config:a", "dna", (0, 0)));
+        assert!(registry.claim("config:b", "dna", (1, 1)));
+        assert!(registry.owns("config:b", "dna", (1, 1)));
+        assert!(!registry.owns("config:b", "other", (1, 1)));
+    }
+}
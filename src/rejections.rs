@@ -0,0 +1,93 @@
+use std::{collections::BTreeMap, fmt};
+
+use serde::Serialize;
+
+/// Why a drawn combination was discarded
+pub enum Rejection {
+    /// The config already generated this DNA
+    Duplicate,
+    /// The `(trait_name, exclude)` pair is in the blacklist
+    Blacklisted(String, String),
+    /// An exclude turned a layer away from the trait a requirement pins it to
+    Requirement,
+    /// A trait of the combination has no quota left
+    Quota,
+    /// A previous drop already has this DNA
+    Dropped,
+    /// Another config of the same uniqueness scope already claimed this DNA
+    Taken,
+}
+
+/// Rejection counts of a config
+#[derive(Serialize, Default)]
+pub struct Rejections {
+    pub config_name: String,
+    pub accepted: usize,
+    pub duplicate: usize,
+    pub blacklisted: usize,
+    pub requirement: usize,
+    pub quota: usize,
+    pub dropped: usize,
+    pub taken: usize,
+    /// Blacklist hits per `trait_name + exclude` pair
+    pub blacklist_pairs: BTreeMap<String, usize>,
+}
+
+impl Rejections {
+    pub fn new(config_name: &str) -> Self {
+        Self {
+            config_name: config_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn count(&mut self, rejection: Rejection) {
+        match rejection {
+            Rejection::Duplicate => self.duplicate += 1,
+            Rejection::Blacklisted(trait_name, exclude) => {
+                self.blacklisted += 1;
+                *self
+                    .blacklist_pairs
+                    .entry(format!("{} + {}", trait_name, exclude))
+                    .or_default() += 1;
+            }
+            Rejection::Requirement => self.requirement += 1,
+            Rejection::Quota => self.quota += 1,
+            Rejection::Dropped => self.dropped += 1,
+            Rejection::Taken => self.taken += 1,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.duplicate
+            + self.blacklisted
+            + self.requirement
+            + self.quota
+            + self.dropped
+            + self.taken
+    }
+}
+
+impl fmt::Display for Rejections {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} accepted, {} rejected ({} duplicate, {} blacklisted, {} breaking a requirement, {} out of quota, {} in previous drops, {} taken by other configs)",
+            self.config_name,
+            self.accepted,
+            self.total(),
+            self.duplicate,
+            self.blacklisted,
+            self.requirement,
+            self.quota,
+            self.dropped,
+            self.taken
+        )?;
+
+        for (pair, count) in &self.blacklist_pairs {
+            write!(f, "\n  {} -> {}", pair, count)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,421 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use anyhow::anyhow;
+use image::{imageops, Delay, Frame, Rgba, RgbaImage};
+
+use crate::{
+    cache::ImageCache,
+    config::{
+        parse_color, AppConfig, Direction, FillTrait, Format, LayerConfig, OutputSize,
+        VariantConfig,
+    },
+    layers::Layers,
+    utils,
+};
+
+/// Upper bound of frames of an animated token
+pub const MAX_FRAMES: usize = 1000;
+
+/// Composites the traits of a combination, frame by frame
+///
+/// Animated traits loop until they line up again, still traits repeat on
+/// every frame. Frame delays come from the trait with the most frames.
+pub fn render(
+    config: &AppConfig,
+    layers: &Layers,
+    combination: &[usize],
+    cache: &ImageCache,
+) -> anyhow::Result<Vec<Frame>> {
+    let canvas = (layers.width, layers.height);
+
+    let traits = combination
+        .iter()
+        .zip(&layers.trait_sets)
+        .zip(&config.layers)
+        .filter_map(|((index, trait_list), layer_config)| {
+            let nft_trait = &trait_list[*index];
+            match (&nft_trait.path, &nft_trait.fill) {
+                (Some(path), _) => Some(
+                    match &nft_trait.recolor {
+                        Some(recolor) => cache.get_recolored(path, recolor),
+                        None => cache.get(path),
+                    }
+                    .map(|frames| (frames, layer_config)),
+                ),
+                (None, Some(fill)) => Some(Ok((
+                    Arc::new(vec![Frame::new(fill.draw(canvas))]),
+                    layer_config,
+                ))),
+                (None, None) => None,
+            }
+        })
+        .collect::<anyhow::Result<Vec<(Arc<Vec<Frame>>, &LayerConfig)>>>()?;
+
+    let counts = traits
+        .iter()
+        .map(|(frames, _)| frames.len())
+        .collect::<Vec<usize>>();
+    let timing = traits
+        .iter()
+        .map(|(frames, _)| frames)
+        .reduce(|longest, frames| {
+            if frames.len() > longest.len() {
+                frames
+            } else {
+                longest
+            }
+        });
+
+    let frames = (0..frame_count(&counts))
+        .map(|frame_idx| {
+            let mut base = RgbaImage::new(canvas.0, canvas.1);
+
+            for (frames, layer_config) in &traits {
+                let frame = &frames[frame_idx % frames.len()];
+                let (image, x, y) = utils::place(frame.buffer(), canvas, layer_config);
+                utils::blend(
+                    &mut base,
+                    &image,
+                    x,
+                    y,
+                    layer_config.blend.unwrap_or_default(),
+                    layer_config.opacity.unwrap_or(1.0),
+                );
+            }
+
+            let delay = timing.map_or(Delay::from_numer_denom_ms(0, 1), |frames| {
+                frames[frame_idx % frames.len()].delay()
+            });
+            Frame::from_parts(base, 0, 0, delay)
+        })
+        .collect();
+
+    Ok(frames)
+}
+
+/// Color or gradient a fill trait is drawn with, at canvas size
+#[derive(Debug, Clone)]
+pub enum Fill {
+    Color([u8; 3]),
+    Gradient(Vec<[u8; 3]>, Direction),
+}
+
+impl Fill {
+    pub fn new(fill: &FillTrait) -> anyhow::Result<Self> {
+        match (&fill.color, &fill.gradient) {
+            (Some(color), None) => Ok(Fill::Color(parse_color(color)?)),
+            (None, Some(gradient)) if gradient.stops.len() >= 2 => Ok(Fill::Gradient(
+                gradient
+                    .stops
+                    .iter()
+                    .map(|stop| parse_color(stop))
+                    .collect::<anyhow::Result<Vec<[u8; 3]>>>()?,
+                gradient.direction,
+            )),
+            (None, Some(_)) => Err(anyhow!(
+                "gradient of fill {} needs at least 2 stops",
+                fill.name
+            )),
+            _ => Err(anyhow!(
+                "fill {} needs either a color or a gradient",
+                fill.name
+            )),
+        }
+    }
+
+    pub fn draw(&self, canvas: (u32, u32)) -> RgbaImage {
+        let (width, height) = canvas;
+
+        match self {
+            Fill::Color([r, g, b]) => RgbaImage::from_pixel(width, height, Rgba([*r, *g, *b, 255])),
+            Fill::Gradient(stops, direction) => RgbaImage::from_fn(width, height, |x, y| {
+                // position along the gradient, from 0 to 1
+                let (along, length) = match direction {
+                    Direction::Vertical => (y, height),
+                    Direction::Horizontal => (x, width),
+                    Direction::Diagonal => (x + y, width + height - 1),
+                };
+                let t = along as f32 / length.saturating_sub(1).max(1) as f32;
+
+                let segment = t * (stops.len() - 1) as f32;
+                let index = (segment.floor() as usize).min(stops.len() - 2);
+                let local = segment - index as f32;
+
+                let (from, to) = (stops[index], stops[index + 1]);
+                Rgba([
+                    lerp(from[0], to[0], local),
+                    lerp(from[1], to[1], local),
+                    lerp(from[2], to[2], local),
+                    255,
+                ])
+            }),
+        }
+    }
+}
+
+/// How a variant recolors the image of its trait
+#[derive(Debug, Clone)]
+pub enum Recolor {
+    Hue(f32),
+    Palette(HashMap<[u8; 3], [u8; 3]>),
+}
+
+impl Recolor {
+    pub fn new(variant: &VariantConfig) -> anyhow::Result<Self> {
+        match (variant.hue, &variant.palette) {
+            (Some(hue), None) => Ok(Recolor::Hue(hue)),
+            (None, Some(palette)) => Ok(Recolor::Palette(
+                palette
+                    .iter()
+                    .map(|(from, to)| Ok((parse_color(from)?, parse_color(to)?)))
+                    .collect::<anyhow::Result<HashMap<[u8; 3], [u8; 3]>>>()?,
+            )),
+            _ => Err(anyhow!(
+                "variant {} needs either a hue or a palette",
+                variant.name
+            )),
+        }
+    }
+
+    /// Identifies the recolor in the image cache, palettes in color order
+    pub fn key(&self) -> String {
+        match self {
+            Recolor::Hue(degrees) => format!("hue:{}", degrees),
+            Recolor::Palette(palette) => {
+                let mut pairs = palette.iter().collect::<Vec<_>>();
+                pairs.sort();
+                format!("palette:{:?}", pairs)
+            }
+        }
+    }
+
+    /// Recolored copies of the frames, alpha is left as is
+    pub fn apply(&self, frames: &[Frame]) -> Vec<Frame> {
+        frames
+            .iter()
+            .map(|frame| {
+                let mut image = frame.buffer().clone();
+                for pixel in image.pixels_mut() {
+                    let rgb = [pixel[0], pixel[1], pixel[2]];
+                    let [r, g, b] = match self {
+                        Recolor::Hue(degrees) => rotate_hue(rgb, *degrees),
+                        Recolor::Palette(palette) => *palette.get(&rgb).unwrap_or(&rgb),
+                    };
+                    *pixel = Rgba([r, g, b, pixel[3]]);
+                }
+                Frame::from_parts(image, frame.left(), frame.top(), frame.delay())
+            })
+            .collect()
+    }
+}
+
+/// Rotates the hue of a color in HSV space
+fn rotate_hue(rgb: [u8; 3], degrees: f32) -> [u8; 3] {
+    let [r, g, b] = rgb.map(|c| c as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let chroma = max - r.min(g).min(b);
+    if chroma == 0.0 {
+        // greys have no hue
+        return rgb;
+    }
+
+    let hue = if max == r {
+        ((g - b) / chroma).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / chroma + 2.0
+    } else {
+        (r - g) / chroma + 4.0
+    };
+    let hue = (hue * 60.0 + degrees).rem_euclid(360.0) / 60.0;
+
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let min = max - chroma;
+
+    [r, g, b].map(|c| ((c + min) * 255.0).round().clamp(0.0, 255.0) as u8)
+}
+
+fn lerp(from: u8, to: u8, t: f32) -> u8 {
+    (from as f32 + (to as f32 - from as f32) * t).round() as u8
+}
+
+/// Frames needed for every trait to loop cleanly, or the most frames of a
+/// trait when that is more than `MAX_FRAMES`
+fn frame_count(counts: &[usize]) -> usize {
+    let longest = counts.iter().copied().max().unwrap_or(1);
+
+    let mut lcm = 1;
+    for count in counts {
+        lcm = lcm / utils::gcd(lcm, *count) * count;
+        if lcm > MAX_FRAMES {
+            return longest;
+        }
+    }
+
+    lcm
+}
+
+/// Frames resized to one of the config `sizes`
+pub fn resize(frames: &[Frame], size: &OutputSize) -> Vec<Frame> {
+    frames
+        .iter()
+        .map(|frame| {
+            let (width, height) = size.resolve(frame.buffer().dimensions());
+            let resized = imageops::resize(
+                frame.buffer(),
+                width,
+                height,
+                size.filter.unwrap_or_default().into(),
+            );
+            Frame::from_parts(resized, 0, 0, frame.delay())
+        })
+        .collect()
+}
+
+/// Writes a rendered token, as an animation when it has animated traits
+pub fn save(frames: &[Frame], path: &Path, format: &Format, animated: bool) -> anyhow::Result<()> {
+    if animated {
+        utils::save_animation(frames, path, format)
+    } else {
+        utils::save(frames[0].buffer(), path, format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Gradient;
+
+    fn fill(color: Option<&str>, stops: &[&str], direction: Direction) -> FillTrait {
+        FillTrait {
+            name: String::from("sky"),
+            color: color.map(String::from),
+            gradient: (!stops.is_empty()).then(|| Gradient {
+                stops: stops.iter().map(|stop| stop.to_string()).collect(),
+                direction,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn draws_a_solid_color() {
+        let image = Fill::new(&fill(Some("#ff8000"), &[], Direction::Vertical))
+            .unwrap()
+            .draw((2, 3));
+
+        assert_eq!(image.dimensions(), (2, 3));
+        assert!(image.pixels().all(|pixel| pixel.0 == [255, 128, 0, 255]));
+    }
+
+    #[test]
+    fn spreads_gradient_stops_from_start_to_end() {
+        let gradient = |direction| {
+            Fill::new(&fill(None, &["#000000", "#ffffff", "#000000"], direction))
+                .unwrap()
+                .draw((5, 3))
+        };
+
+        let horizontal = gradient(Direction::Horizontal);
+        assert_eq!(
+            (0..5)
+                .map(|x| horizontal.get_pixel(x, 2)[0])
+                .collect::<Vec<u8>>(),
+            vec![0, 128, 255, 128, 0]
+        );
+
+        let vertical = gradient(Direction::Vertical);
+        assert_eq!(
+            (0..3)
+                .map(|y| vertical.get_pixel(4, y)[0])
+                .collect::<Vec<u8>>(),
+            vec![0, 255, 0]
+        );
+
+        // corner to corner
+        let diagonal = gradient(Direction::Diagonal);
+        assert_eq!(diagonal.get_pixel(0, 0)[0], 0);
+        assert_eq!(diagonal.get_pixel(4, 2)[0], 0);
+        assert_eq!(diagonal.get_pixel(3, 0)[0], 255);
+    }
+
+    #[test]
+    fn needs_a_color_or_two_stops() {
+        assert!(Fill::new(&fill(None, &[], Direction::Vertical)).is_err());
+        assert!(Fill::new(&fill(None, &["#000000"], Direction::Vertical)).is_err());
+        assert!(Fill::new(&fill(
+            Some("#000000"),
+            &["#000000", "#ffffff"],
+            Direction::Vertical
+        ))
+        .is_err());
+    }
+
+    fn variant(hue: Option<f32>, palette: &[(&str, &str)]) -> VariantConfig {
+        VariantConfig {
+            base: String::from("hat"),
+            name: String::from("blue hat"),
+            hue,
+            palette: (!palette.is_empty()).then(|| {
+                palette
+                    .iter()
+                    .map(|(from, to)| (from.to_string(), to.to_string()))
+                    .collect()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn recolor(recolor: &Recolor, pixels: &[[u8; 4]]) -> Vec<[u8; 4]> {
+        let image = RgbaImage::from_fn(pixels.len() as u32, 1, |x, _| Rgba(pixels[x as usize]));
+        let frames = recolor.apply(&[Frame::new(image)]);
+
+        frames[0].buffer().pixels().map(|pixel| pixel.0).collect()
+    }
+
+    #[test]
+    fn rotates_hues_but_not_greys() {
+        let hue = Recolor::new(&variant(Some(120.0), &[])).unwrap();
+
+        assert_eq!(
+            recolor(
+                &hue,
+                &[[255, 0, 0, 255], [0, 0, 255, 128], [90, 90, 90, 255]]
+            ),
+            vec![[0, 255, 0, 255], [255, 0, 0, 128], [90, 90, 90, 255]]
+        );
+    }
+
+    #[test]
+    fn swaps_palette_colors_only() {
+        let palette = Recolor::new(&variant(None, &[("#ff0000", "#0000ff")])).unwrap();
+
+        assert_eq!(
+            recolor(&palette, &[[255, 0, 0, 64], [254, 0, 0, 255]]),
+            vec![[0, 0, 255, 64], [254, 0, 0, 255]]
+        );
+    }
+
+    #[test]
+    fn keys_recolors_by_content() {
+        let key = |palette: &[(&str, &str)]| Recolor::new(&variant(None, palette)).unwrap().key();
+
+        assert_eq!(
+            key(&[("#ff0000", "#0000ff"), ("#00ff00", "#ffffff")]),
+            key(&[("#00ff00", "#ffffff"), ("#ff0000", "#0000ff")])
+        );
+        assert_ne!(
+            key(&[("#ff0000", "#0000ff")]),
+            key(&[("#ff0000", "#00ff00")])
+        );
+        assert!(Recolor::new(&variant(Some(10.0), &[("#ff0000", "#0000ff")])).is_err());
+        assert!(Recolor::new(&variant(None, &[])).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use std::{collections::HashMap, fmt::Debug, fs, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Sidecar of every trait of a layer folder, keyed by image file stem
+pub const LAYER_SIDECAR: &str = "_traits.json";
+
+/// Metadata of a trait image, read from `<image stem>.json` next to it or from
+/// its entry in the `_traits.json` of the layer folder
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Sidecar {
+    /// Trait name with a `#`, which the file name can't hold
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    /// Shown in the metadata instead of the trait name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Added to the metadata attributes of tokens with the trait
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<Map<String, Value>>,
+}
+
+impl Sidecar {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("unable to read sidecar {}", path.display()))?;
+
+        serde_json::from_str(&contents)
+            .with_context(|| format!("unable to parse sidecar {}", path.display()))
+    }
+
+    pub fn load_layer(path: &Path) -> anyhow::Result<HashMap<String, Self>> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("unable to read sidecar {}", path.display()))?;
+
+        serde_json::from_str(&contents)
+            .with_context(|| format!("unable to parse sidecar {}", path.display()))
+    }
+
+    /// Fills the fields missing from `self` with `other`, fields both set to
+    /// different values are listed in `conflicts`
+    pub fn merge(mut self, other: Sidecar, source: &str, conflicts: &mut Vec<String>) -> Self {
+        merge_field(&mut self.name, other.name, "name", source, conflicts);
+        merge_field(&mut self.weight, other.weight, "weight", source, conflicts);
+        merge_field(
+            &mut self.display_name,
+            other.display_name,
+            "display_name",
+            source,
+            conflicts,
+        );
+        merge_field(&mut self.tags, other.tags, "tags", source, conflicts);
+        merge_field(
+            &mut self.description,
+            other.description,
+            "description",
+            source,
+            conflicts,
+        );
+        merge_field(
+            &mut self.attributes,
+            other.attributes,
+            "attributes",
+            source,
+            conflicts,
+        );
+
+        self
+    }
+}
+
+fn merge_field<T: PartialEq + Debug>(
+    field: &mut Option<T>,
+    other: Option<T>,
+    field_name: &str,
+    source: &str,
+    conflicts: &mut Vec<String>,
+) {
+    match (field.as_ref(), other) {
+        (Some(value), Some(other)) if value != &other => conflicts.push(format!(
+            "{}: {} is {:?} but {:?} in {}",
+            source, field_name, value, other, LAYER_SIDECAR
+        )),
+        (None, other) => *field = other,
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_missing_fields() {
+        let sidecar = Sidecar {
+            weight: Some(2),
+            ..Default::default()
+        };
+        let layer_sidecar = Sidecar {
+            weight: Some(2),
+            display_name: Some(String::from("Golden Crown")),
+            ..Default::default()
+        };
+        let mut conflicts = vec![];
+
+        let merged = sidecar.merge(layer_sidecar, "hat/crown.json", &mut conflicts);
+
+        assert!(conflicts.is_empty());
+        assert_eq!(merged.weight, Some(2));
+        assert_eq!(merged.display_name.as_deref(), Some("Golden Crown"));
+    }
+
+    #[test]
+    fn lists_fields_set_to_different_values() {
+        let sidecar = Sidecar {
+            name: Some(String::from("#1 Fan")),
+            weight: Some(2),
+            ..Default::default()
+        };
+        let layer_sidecar = Sidecar {
+            name: Some(String::from("#1 Fan")),
+            weight: Some(5),
+            ..Default::default()
+        };
+        let mut conflicts = vec![];
+
+        let merged = sidecar.merge(layer_sidecar, "hat/fan.json", &mut conflicts);
+
+        assert_eq!(
+            conflicts,
+            vec!["hat/fan.json: weight is 2 but 5 in _traits.json"]
+        );
+        // the sidecar next to the image wins
+        assert_eq!(merged.weight, Some(2));
+    }
+}
@@ -1,14 +1,303 @@
-use std::{fs, path::Path};
+use std::{
+    borrow::Cow,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::Path,
+};
 
-use anyhow::Context;
-use image::{imageops, GenericImage, GenericImageView};
+use anyhow::{anyhow, Context};
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType as PngFilterType, PngDecoder, PngEncoder},
+        webp::{WebPDecoder, WebPEncoder, WebPQuality},
+    },
+    imageops, AnimationDecoder, ColorType, Frame, GenericImage, GenericImageView, ImageEncoder,
+    ImageFormat, Rgb, RgbImage, RgbaImage,
+};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use sha3::{Digest, Keccak256};
 
-pub fn merge<I, J>(bottom: &mut I, top: &J)
+use crate::config::{parse_color, Blend, Compression, Format, LayerConfig, Scale};
+
+pub fn merge<I, J>(bottom: &mut I, top: &J, x: i64, y: i64)
 where
     I: GenericImage,
     J: GenericImageView<Pixel = I::Pixel>,
 {
-    imageops::overlay(bottom, top, 0, 0);
+    imageops::overlay(bottom, top, x, y);
+}
+
+/// Resizes and positions a trait image on the canvas as its layer asks
+pub fn place<'a>(
+    image: &'a RgbaImage,
+    canvas: (u32, u32),
+    layer_config: &LayerConfig,
+) -> (Cow<'a, RgbaImage>, i64, i64) {
+    let (width, height) = image.dimensions();
+
+    let size = match layer_config.scale.unwrap_or_default() {
+        Scale::None => (width, height),
+        Scale::Stretch => canvas,
+        Scale::Fit => {
+            let ratio = f64::min(
+                canvas.0 as f64 / width as f64,
+                canvas.1 as f64 / height as f64,
+            );
+            (
+                ((width as f64 * ratio).round() as u32).max(1),
+                ((height as f64 * ratio).round() as u32).max(1),
+            )
+        }
+    };
+    let image = if size == (width, height) {
+        Cow::Borrowed(image)
+    } else {
+        Cow::Owned(imageops::resize(
+            image,
+            size.0,
+            size.1,
+            imageops::FilterType::Lanczos3,
+        ))
+    };
+
+    let (x, y) = layer_config.anchor.unwrap_or_default().place(canvas, size);
+
+    (
+        image,
+        x + layer_config.x.unwrap_or(0),
+        y + layer_config.y.unwrap_or(0),
+    )
+}
+
+/// Composites `top` onto `bottom` at (`x`, `y`) with a blend mode and an opacity
+pub fn blend(bottom: &mut RgbaImage, top: &RgbaImage, x: i64, y: i64, blend: Blend, opacity: f32) {
+    if blend == Blend::Normal && opacity >= 1.0 {
+        merge(bottom, top, x, y);
+        return;
+    }
+
+    for (top_x, top_y, source) in top.enumerate_pixels() {
+        let (Ok(x), Ok(y)) = (
+            u32::try_from(x + top_x as i64),
+            u32::try_from(y + top_y as i64),
+        ) else {
+            continue;
+        };
+        if x >= bottom.width() || y >= bottom.height() {
+            continue;
+        }
+        let backdrop = bottom.get_pixel_mut(x, y);
+
+        let alpha_s = source[3] as f32 / 255.0 * opacity;
+        let alpha_b = backdrop[3] as f32 / 255.0;
+        let alpha_o = alpha_s + alpha_b * (1.0 - alpha_s);
+        if alpha_o <= 0.0 {
+            continue;
+        }
+
+        for c in 0..3 {
+            let cs = source[c] as f32 / 255.0;
+            let cb = backdrop[c] as f32 / 255.0;
+            // blended color only shows where there is a backdrop
+            let mixed = (1.0 - alpha_b) * cs + alpha_b * blend_channel(blend, cb, cs);
+            let co = alpha_s * mixed + alpha_b * cb * (1.0 - alpha_s);
+
+            backdrop[c] = (co / alpha_o * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+        backdrop[3] = (alpha_o * 255.0).round().clamp(0.0, 255.0) as u8;
+    }
+}
+
+/// Separable blend functions of the W3C compositing spec, on 0-1 channels
+fn blend_channel(blend: Blend, cb: f32, cs: f32) -> f32 {
+    match blend {
+        Blend::Normal => cs,
+        Blend::Multiply => cb * cs,
+        Blend::Screen => cb + cs - cb * cs,
+        Blend::Overlay => {
+            if cb <= 0.5 {
+                cs * 2.0 * cb
+            } else {
+                let cb = 2.0 * cb - 1.0;
+                cs + cb - cs * cb
+            }
+        }
+        Blend::Add => (cb + cs).min(1.0),
+        Blend::SoftLight => {
+            if cs <= 0.5 {
+                cb - (1.0 - 2.0 * cs) * cb * (1.0 - cb)
+            } else {
+                let d = if cb <= 0.25 {
+                    ((16.0 * cb - 12.0) * cb + 4.0) * cb
+                } else {
+                    cb.sqrt()
+                };
+                cb + (2.0 * cs - 1.0) * (d - cb)
+            }
+        }
+    }
+}
+
+/// Encodes an image to `path` in the output format
+pub fn save(image: &RgbaImage, path: &Path, format: &Format) -> anyhow::Result<()> {
+    let file = BufWriter::new(
+        File::create(path).with_context(|| format!("unable to create {}", path.display()))?,
+    );
+    let (width, height) = image.dimensions();
+
+    match format {
+        Format::Png { compression } => {
+            let compression = match compression.unwrap_or_default() {
+                Compression::Fast => CompressionType::Fast,
+                Compression::Default => CompressionType::Default,
+                Compression::Best => CompressionType::Best,
+            };
+            PngEncoder::new_with_quality(file, compression, PngFilterType::Adaptive).write_image(
+                image,
+                width,
+                height,
+                ColorType::Rgba8,
+            )?;
+        }
+        Format::Jpeg {
+            quality,
+            background,
+        } => {
+            // jpeg has no alpha, transparent pixels take the background color
+            let background = background.as_deref().map_or(Ok([255; 3]), parse_color)?;
+            let flattened = RgbImage::from_fn(width, height, |x, y| {
+                let pixel = image.get_pixel(x, y);
+                let alpha = pixel[3] as u32;
+                Rgb(std::array::from_fn(|c| {
+                    ((pixel[c] as u32 * alpha + background[c] as u32 * (255 - alpha) + 127) / 255)
+                        as u8
+                }))
+            });
+            JpegEncoder::new_with_quality(file, quality.unwrap_or(90)).write_image(
+                &flattened,
+                width,
+                height,
+                ColorType::Rgb8,
+            )?;
+        }
+        Format::Webp { lossless, quality } => {
+            let quality = if *lossless {
+                WebPQuality::lossless()
+            } else {
+                WebPQuality::lossy(quality.unwrap_or(WebPQuality::DEFAULT))
+            };
+            WebPEncoder::new_with_quality(file, quality).encode(
+                image,
+                width,
+                height,
+                ColorType::Rgba8,
+            )?;
+        }
+        Format::Gif => {
+            GifEncoder::new(file).encode(image, width, height, ColorType::Rgba8)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Encodes frames to `path` as an animated PNG or GIF
+pub fn save_animation(frames: &[Frame], path: &Path, format: &Format) -> anyhow::Result<()> {
+    let file = BufWriter::new(
+        File::create(path).with_context(|| format!("unable to create {}", path.display()))?,
+    );
+
+    match format {
+        Format::Png { compression } => {
+            let (width, height) = frames[0].buffer().dimensions();
+            let mut encoder = png::Encoder::new(file, width, height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_compression(match compression.unwrap_or_default() {
+                Compression::Fast => png::Compression::Fast,
+                Compression::Default => png::Compression::Default,
+                Compression::Best => png::Compression::Best,
+            });
+            // loops forever
+            encoder.set_animated(frames.len() as u32, 0)?;
+
+            let mut writer = encoder.write_header()?;
+            for frame in frames {
+                let (numerator, denominator) = frame.delay().numer_denom_ms();
+                let delay = (numerator / denominator.max(1)).min(u16::MAX as u32) as u16;
+                writer.set_frame_delay(delay, 1000)?;
+                writer.write_image_data(frame.buffer().as_raw())?;
+            }
+            writer.finish()?;
+        }
+        Format::Gif => {
+            let mut encoder = GifEncoder::new(file);
+            encoder.set_repeat(Repeat::Infinite)?;
+            encoder.encode_frames(frames.iter().cloned())?;
+        }
+        _ => {
+            return Err(anyhow!(
+                "{} can't be animated, use png or gif",
+                format.extension()
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+/// Kind of animation of a trait image, if it is animated
+pub enum Animation {
+    Gif,
+    Apng,
+    WebP,
+}
+
+/// GIF, PNG and WebP images are animated when they have more than one frame
+pub fn animation(path: &Path) -> anyhow::Result<Option<Animation>> {
+    let read = || -> anyhow::Result<BufReader<File>> {
+        Ok(BufReader::new(File::open(path).with_context(|| {
+            format!("failed to load image {}", path.display())
+        })?))
+    };
+
+    match ImageFormat::from_path(path) {
+        Ok(ImageFormat::Gif) => {
+            let decoder = GifDecoder::new(read()?)
+                .with_context(|| format!("failed to load image {}", path.display()))?;
+            // a still GIF decodes to a single frame
+            let animated = decoder.into_frames().take(2).count() == 2;
+            Ok(animated.then_some(Animation::Gif))
+        }
+        Ok(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(read()?)
+                .with_context(|| format!("failed to load image {}", path.display()))?;
+            // an APNG can hold a single frame, which is still
+            let animated = decoder.is_apng() && decoder.apng().into_frames().take(2).count() == 2;
+            Ok(animated.then_some(Animation::Apng))
+        }
+        Ok(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(read()?)
+                .with_context(|| format!("failed to load image {}", path.display()))?;
+            let animated = decoder.has_animation() && decoder.into_frames().take(2).count() == 2;
+            Ok(animated.then_some(Animation::WebP))
+        }
+        _ => Ok(None),
+    }
+}
+
+pub fn gcd<T>(a: T, b: T) -> T
+where
+    T: Copy + PartialEq + Default + std::ops::Rem<Output = T>,
+{
+    if b == T::default() {
+        a
+    } else {
+        gcd(b, a % b)
+    }
 }
 
 pub fn clean(output: &Path) -> anyhow::Result<()> {
@@ -19,3 +308,47 @@ pub fn clean(output: &Path) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Derives the RNG of a config from the run seed and the config name, so every
+/// config draws the same sequence no matter how the configs are scheduled.
+pub fn config_rng(seed: u64, config_name: &str) -> ChaCha8Rng {
+    let mut hasher = Keccak256::new();
+    hasher.update(seed.to_le_bytes());
+    hasher.update(config_name.as_bytes());
+
+    ChaCha8Rng::from_seed(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blends_channels() {
+        let cases = [
+            (Blend::Normal, 0.2, 0.7, 0.7),
+            (Blend::Multiply, 0.5, 0.5, 0.25),
+            (Blend::Screen, 0.5, 0.5, 0.75),
+            (Blend::Overlay, 0.25, 1.0, 0.5),
+            (Blend::Overlay, 0.75, 0.5, 0.75),
+            (Blend::Add, 0.75, 0.5, 1.0),
+            (Blend::SoftLight, 0.5, 0.5, 0.5),
+            (Blend::SoftLight, 0.25, 0.0, 0.0625),
+            (Blend::SoftLight, 0.25, 1.0, 0.5),
+            (Blend::SoftLight, 0.64, 1.0, 0.8),
+        ];
+
+        for (blend, cb, cs, expected) in cases {
+            let blended = blend_channel(blend, cb, cs);
+            assert!(
+                (blended - expected).abs() < 1e-6,
+                "{:?}({}, {}) is {}, not {}",
+                blend,
+                cb,
+                cs,
+                blended,
+                expected
+            );
+        }
+    }
+}